@@ -0,0 +1,8 @@
+-- Deployments from before migrations already have this table, with balances as floats
+create table if not exists users (
+    telegram_id bigint primary key,
+    name text not null,
+    balance_reais numeric(12, 2) not null default 0
+);
+
+alter table users alter column balance_reais type numeric(12, 2);
@@ -0,0 +1,20 @@
+create table if not exists ledger (
+    id bigserial primary key,
+    telegram_id bigint not null references users (telegram_id),
+    -- top_up, cycle_charge, adjustment or refund
+    kind text not null,
+    amount_reais numeric(12, 2) not null,
+    balance_after_reais numeric(12, 2) not null,
+    description text not null,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists ledger_telegram_id_created_at on ledger (telegram_id, created_at);
+
+-- Balances from before the ledger existed are opened by an adjustment, so every balance is
+-- the sum of its ledger entries
+insert into ledger (telegram_id, kind, amount_reais, balance_after_reais, description)
+select telegram_id, 'adjustment', balance_reais, balance_reais, 'Saldo inicial'
+from users
+where balance_reais <> 0
+  and not exists (select 1 from ledger where ledger.telegram_id = users.telegram_id);
@@ -0,0 +1,33 @@
+create table if not exists cycles (
+    id bigserial primary key,
+    telegram_id bigint not null references users (telegram_id),
+    started_at timestamptz not null,
+    ended_at timestamptz not null,
+    energy_wh bigint not null,
+    cost_reais numeric(12, 2) not null,
+    -- out_of_money, idle, meter_failure, restart, restart_energy_unknown, emergency or forced
+    end_reason text not null
+);
+
+create index if not exists cycles_telegram_id_started_at on cycles (telegram_id, started_at);
+
+-- There is a single dryer, so at most one cycle running
+create table if not exists active_cycle (
+    id smallint primary key default 1 check (id = 1),
+    telegram_id bigint not null references users (telegram_id),
+    chat_id bigint not null,
+    started_at timestamptz not null,
+    start_meter_energy_wh bigint not null,
+    last_meter_energy_wh bigint not null,
+    consumed_wh bigint not null,
+    carried_millicentavos bigint not null,
+    charged_reais numeric(12, 2) not null,
+    updated_at timestamptz not null default now()
+);
+
+create table if not exists hardware_faults (
+    id bigserial primary key,
+    occurred_at timestamptz not null default now(),
+    kind text not null,
+    details text not null
+);
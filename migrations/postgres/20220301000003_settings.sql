@@ -0,0 +1,6 @@
+-- Configuration changed at runtime, like by admins through the bot
+create table if not exists settings (
+    key text primary key,
+    value text not null,
+    updated_at timestamptz not null default now()
+);
@@ -0,0 +1,7 @@
+-- Registration requests waiting for an admin
+create table pending_users (
+    telegram_id bigint primary key,
+    chat_id bigint not null,
+    name text not null,
+    requested_at timestamptz not null default now()
+);
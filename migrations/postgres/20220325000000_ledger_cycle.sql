@@ -0,0 +1,2 @@
+-- Start of the cycle a cycle_charge entry was billed for, its charges are shown as one entry
+alter table ledger add column cycle_started_at timestamptz;
@@ -0,0 +1,4 @@
+-- One process may drive several machines, numbered from 1, each with its own active cycle
+alter table active_cycle drop constraint active_cycle_id_check;
+alter table active_cycle alter column id drop default;
+alter table active_cycle rename column id to machine;
@@ -0,0 +1,6 @@
+-- Amounts are integer centavos, times are UTC text as written by sqlx, `YYYY-MM-DD HH:MM:SS.SSS`
+create table users (
+    telegram_id integer primary key,
+    name text not null,
+    balance_centavos integer not null default 0
+);
@@ -0,0 +1,12 @@
+create table ledger (
+    id integer primary key autoincrement,
+    telegram_id integer not null references users (telegram_id),
+    -- top_up, cycle_charge, adjustment or refund
+    kind text not null,
+    amount_centavos integer not null,
+    balance_after_centavos integer not null,
+    description text not null,
+    created_at text not null default (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
+
+create index ledger_telegram_id_created_at on ledger (telegram_id, created_at);
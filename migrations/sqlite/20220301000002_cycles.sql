@@ -0,0 +1,33 @@
+create table cycles (
+    id integer primary key autoincrement,
+    telegram_id integer not null references users (telegram_id),
+    started_at text not null,
+    ended_at text not null,
+    energy_wh integer not null,
+    cost_centavos integer not null,
+    -- out_of_money, idle, meter_failure, restart, restart_energy_unknown, emergency or forced
+    end_reason text not null
+);
+
+create index cycles_telegram_id_started_at on cycles (telegram_id, started_at);
+
+-- There is a single dryer, so at most one cycle running
+create table active_cycle (
+    id integer primary key default 1 check (id = 1),
+    telegram_id integer not null references users (telegram_id),
+    chat_id integer not null,
+    started_at text not null,
+    start_meter_energy_wh integer not null,
+    last_meter_energy_wh integer not null,
+    consumed_wh integer not null,
+    carried_millicentavos integer not null,
+    charged_centavos integer not null,
+    updated_at text not null default (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
+
+create table hardware_faults (
+    id integer primary key autoincrement,
+    occurred_at text not null default (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    kind text not null,
+    details text not null
+);
@@ -0,0 +1,6 @@
+-- Configuration changed at runtime, like by admins through the bot
+create table settings (
+    key text primary key,
+    value text not null,
+    updated_at text not null default (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
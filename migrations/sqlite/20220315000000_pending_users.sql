@@ -0,0 +1,7 @@
+-- Registration requests waiting for an admin
+create table pending_users (
+    telegram_id integer primary key,
+    chat_id integer not null,
+    name text not null,
+    requested_at text not null
+);
@@ -0,0 +1,21 @@
+-- One process may drive several machines, numbered from 1, each with its own active cycle.
+-- SQLite can't drop the check keeping a single row, so the table is rebuilt.
+create table active_cycle_by_machine (
+    machine integer primary key,
+    telegram_id integer not null references users (telegram_id),
+    chat_id integer not null,
+    started_at text not null,
+    start_meter_energy_wh integer not null,
+    last_meter_energy_wh integer not null,
+    consumed_wh integer not null,
+    carried_millicentavos integer not null,
+    charged_centavos integer not null,
+    updated_at text not null default (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+);
+
+insert into active_cycle_by_machine (machine, telegram_id, chat_id, started_at, start_meter_energy_wh, last_meter_energy_wh, consumed_wh, carried_millicentavos, charged_centavos, updated_at)
+select id, telegram_id, chat_id, started_at, start_meter_energy_wh, last_meter_energy_wh, consumed_wh, carried_millicentavos, charged_centavos, updated_at from active_cycle;
+
+drop table active_cycle;
+
+alter table active_cycle_by_machine rename to active_cycle;
@@ -0,0 +1,306 @@
+use crate::database::{Database, TransactionKind, User};
+use crate::dryers::Dryers;
+use crate::money::{EnergyPrice, Money};
+use crate::telegram::{AdminCommand, Buttons, OutgoingMessage, UserMessage};
+use std::sync::mpsc::SyncSender;
+
+/// Setting the kWh price is stored under, in centavos
+const PRICE_SETTING: &str = "kwh_price_centavos";
+
+/// The kWh price set by an admin, if any, is applied to the dryers
+pub async fn load_price(database: &Database, dryers: &mut Dryers) {
+    match database.get_setting(PRICE_SETTING).await {
+        Ok(Some(centavos)) => match centavos.parse() {
+            Ok(centavos) => dryers.set_price(EnergyPrice::from_centavos_per_kwh(centavos)),
+            Err(e) => log::error!("Invalid {} setting `{}`: {}", PRICE_SETTING, centavos, e),
+        },
+        Ok(None) => {}
+        Err(e) => log::error!("Error loading the kWh price: {:#?}", e),
+    }
+}
+
+fn notify(telegram_sender: &SyncSender<OutgoingMessage>, chat_id: i64, text: String) {
+    let notification = OutgoingMessage {
+        update_message_with_id: None,
+        chat_id,
+        text,
+        buttons: Buttons::Dryer,
+    };
+    if let Err(e) = telegram_sender.try_send(notification) {
+        log::error!("{:#?}", e);
+    }
+}
+
+/// Private chats have the same id as the user, that is where users are told what admins did
+fn private_chat_id(telegram_id: u64) -> i64 {
+    i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64")
+}
+
+fn describe_user(user: &User) -> String {
+    let mut description = format!("{} ({}): R${}", user.name, user.telegram_id, user.balance);
+    if user.is_admin {
+        description.push_str(" [admin]");
+    }
+    if user.blocked {
+        description.push_str(" [bloqueado]");
+    }
+    description
+}
+
+/// Records a top up, refund or adjustment, telling the user about it, and the dryers too in case
+/// they are drying
+async fn change_balance(
+    database: &Database,
+    dryers: &mut Dryers,
+    telegram_sender: &SyncSender<OutgoingMessage>,
+    user_message: &UserMessage,
+    telegram_id: u64,
+    kind: TransactionKind,
+    amount: Money,
+) -> Result<String, sqlx::Error> {
+    let user = match database.get_db_id(telegram_id).await? {
+        Some(user) => user,
+        None => return Ok(format!("Nenhum usuário com o id {}.", telegram_id)),
+    };
+    let description = match kind {
+        TransactionKind::Refund => format!("Reembolso por {}", user_message.user_name),
+        TransactionKind::Adjustment => format!("Ajuste por {}", user_message.user_name),
+        _ => format!("Crédito por {}", user_message.user_name),
+    };
+    let balance = database
+        .record_transaction(telegram_id, kind, amount, &description)
+        .await?;
+    dryers.balance_changed(telegram_id, balance);
+    let (notification, reply) = match kind {
+        TransactionKind::Refund => (
+            format!(
+                "Você recebeu R${} de reembolso, seu saldo é de R${}.",
+                amount, balance
+            ),
+            format!(
+                "R${} reembolsados para {}, saldo de R${}.",
+                amount, user.name, balance
+            ),
+        ),
+        TransactionKind::Adjustment => (
+            format!(
+                "Seu saldo foi ajustado em R${:+} e agora é de R${}.",
+                amount, balance
+            ),
+            format!(
+                "Saldo de {} ajustado em R${:+}, agora R${}.",
+                user.name, amount, balance
+            ),
+        ),
+        _ => (
+            format!(
+                "Você recebeu R${} de crédito, seu saldo é de R${}.",
+                amount, balance
+            ),
+            format!(
+                "R${} creditados para {}, saldo de R${}.",
+                amount, user.name, balance
+            ),
+        ),
+    };
+    notify(telegram_sender, private_chat_id(telegram_id), notification);
+    Ok(reply)
+}
+
+/// Runs a command already known to come from an admin
+pub async fn handle(
+    database: &Database,
+    dryers: &mut Dryers,
+    telegram_sender: &SyncSender<OutgoingMessage>,
+    user_message: &UserMessage,
+    command: AdminCommand,
+) -> OutgoingMessage {
+    log::info!(
+        "Admin {} ({}) ran {:?}",
+        user_message.user_name,
+        user_message.user_id,
+        command
+    );
+    let text = match run(database, dryers, telegram_sender, user_message, command).await {
+        Ok(text) => text,
+        Err(e) => {
+            log::error!("Error running admin command: {:#?}", e);
+            "Erro ao acessar o banco de dados, tente novamente.".to_string()
+        }
+    };
+    OutgoingMessage {
+        update_message_with_id: None,
+        chat_id: user_message.chat_id,
+        text,
+        buttons: Buttons::None,
+    }
+}
+
+async fn run(
+    database: &Database,
+    dryers: &mut Dryers,
+    telegram_sender: &SyncSender<OutgoingMessage>,
+    user_message: &UserMessage,
+    command: AdminCommand,
+) -> Result<String, sqlx::Error> {
+    let text = match command {
+        AdminCommand::Credit {
+            telegram_id,
+            amount,
+        } => {
+            let kind = TransactionKind::TopUp;
+            change_balance(
+                database,
+                dryers,
+                telegram_sender,
+                user_message,
+                telegram_id,
+                kind,
+                amount,
+            )
+            .await?
+        }
+        AdminCommand::Refund {
+            telegram_id,
+            amount,
+        } => {
+            let kind = TransactionKind::Refund;
+            change_balance(
+                database,
+                dryers,
+                telegram_sender,
+                user_message,
+                telegram_id,
+                kind,
+                amount,
+            )
+            .await?
+        }
+        AdminCommand::Adjust {
+            telegram_id,
+            amount,
+        } => {
+            let kind = TransactionKind::Adjustment;
+            change_balance(
+                database,
+                dryers,
+                telegram_sender,
+                user_message,
+                telegram_id,
+                kind,
+                amount,
+            )
+            .await?
+        }
+        AdminCommand::ListUsers => {
+            let users = database.list_users().await?;
+            if users.is_empty() {
+                "Nenhum usuário registrado.".to_string()
+            } else {
+                let total: Money = users
+                    .iter()
+                    .fold(Money::ZERO, |total, user| total + user.balance);
+                let lines: Vec<String> = users.iter().map(describe_user).collect();
+                format!(
+                    "{} usuários, R${} em saldos:\n{}",
+                    users.len(),
+                    total,
+                    lines.join("\n")
+                )
+            }
+        }
+        AdminCommand::TurnOff { machine } => {
+            let numbers = match machine {
+                Some(number) if number > dryers.count() => {
+                    return Ok(format!("Não existe a secadora {}.", number))
+                }
+                Some(number) => vec![number],
+                None => (1..=dryers.count()).collect(),
+            };
+            let mut lines = vec![];
+            for number in numbers {
+                let prefix = dryers.prefix(number);
+                let dryer = dryers.get_mut(number).expect("Machine numbers are checked");
+                let cycle_stats = match dryer.force_turn_off().await {
+                    Some(cycle_stats) => cycle_stats,
+                    None => continue,
+                };
+                notify(
+                    telegram_sender,
+                    cycle_stats.user.chat_id,
+                    format!(
+                        "{}A secadora foi desligada por um administrador. Custo: R${} referentes a {:.2} kwh consumidos. Saldo remanescente de {}.{}",
+                        prefix,
+                        cycle_stats.charged,
+                        cycle_stats.total_consumed_kwh(),
+                        cycle_stats.user.balance,
+                        crate::relay_warning(dryer)
+                    ),
+                );
+                lines.push(if dryer.relay_stuck() {
+                    format!(
+                        "{}Ciclo de {} encerrado, mas o relé não desligou: a secadora pode continuar ligada.",
+                        prefix, cycle_stats.user.name
+                    )
+                } else {
+                    format!(
+                        "{}Secadora desligada, ciclo de {} encerrado.",
+                        prefix, cycle_stats.user.name
+                    )
+                });
+            }
+            if lines.is_empty() {
+                "A secadora já estava desligada.".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        AdminCommand::Session => {
+            let mut lines = vec![];
+            for number in 1..=dryers.count() {
+                let prefix = dryers.prefix(number);
+                let dryer = dryers.get(number).expect("Machine numbers are checked");
+                lines.push(match dryer.active_cycle() {
+                    Some(cycle_stats) => format!(
+                        "{}{} secando há {}: {:.2} kwh, R${} cobrados, saldo de R${}.",
+                        prefix,
+                        cycle_stats.user.name,
+                        crate::seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
+                        cycle_stats.total_consumed_kwh(),
+                        cycle_stats.charged,
+                        cycle_stats.user.balance,
+                    ),
+                    None => format!("{}Secadora desligada.", prefix),
+                });
+            }
+            format!("{} kWh a R${}.", lines.join("\n"), dryers.price().per_kwh())
+        }
+        AdminCommand::SetPrice { per_kwh } => {
+            database
+                .set_setting(PRICE_SETTING, &per_kwh.centavos().to_string())
+                .await?;
+            let previous = dryers.price().per_kwh();
+            dryers.set_price(EnergyPrice::from_centavos_per_kwh(per_kwh.centavos()));
+            format!(
+                "kWh passou de R${} para R${}, já vale para o ciclo em andamento.",
+                previous, per_kwh
+            )
+        }
+        AdminCommand::Block { telegram_id } => {
+            match database.set_blocked(telegram_id, true).await? {
+                Some(user) => format!(
+                    "{} bloqueado, um ciclo em andamento não é interrompido, use /desligar para isso.",
+                    user.name
+                ),
+                None => format!("Nenhum usuário com o id {}.", telegram_id),
+            }
+        }
+        AdminCommand::Unblock { telegram_id } => {
+            match database.set_blocked(telegram_id, false).await? {
+                Some(user) => format!("{} desbloqueado.", user.name),
+                None => format!("Nenhum usuário com o id {}.", telegram_id),
+            }
+        }
+    };
+    Ok(text)
+}
@@ -1,61 +1,249 @@
-use sqlx::PgPool;
-use std::time::Duration;
+use crate::dryer_manager::{CycleStats, EndReason};
+use crate::money::Money;
+use chrono::{DateTime, Utc};
+use std::ops::Deref;
 
-pub struct Database {
-    con: PgPool,
-}
+mod postgres;
+mod sqlite;
 
-#[derive(Debug, Clone)]
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct User {
     pub telegram_id: i64,
     pub name: String,
-    pub balance_reais: f64,
+    pub balance: Money,
+    /// Besides the admins configured in `ADMIN_USER_IDS`
+    pub is_admin: bool,
+    /// Can't use the dryer
+    pub blocked: bool,
 }
 
-impl Database {
-    pub async fn new() -> Self {
-        loop {
-            let con =
-                sqlx::postgres::PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await;
-            match con {
-                Ok(con) => return Self { con },
-                Err(e) => {
-                    log::error!("{:#?}", e);
-                    tokio::time::sleep(Duration::from_secs(10)).await;
-                }
+/// Cycle that was running when the process last saved it, with its user
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ActiveCycle {
+    /// Where it was running, see [`CycleStats::machine`]
+    pub machine: i64,
+    pub telegram_id: i64,
+    pub chat_id: i64,
+    pub name: String,
+    pub balance: Money,
+    pub started_at: DateTime<Utc>,
+    pub start_meter_energy_wh: i64,
+    pub last_meter_energy_wh: i64,
+    pub consumed_wh: i64,
+    pub carried_millicentavos: i64,
+    pub charged: Money,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A finished cycle
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Cycle {
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub energy_wh: i64,
+    pub cost: Money,
+    pub end_reason: String,
+}
+
+/// Usage over one month
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct MonthlyUsage {
+    pub month: DateTime<Utc>,
+    pub cycles: i64,
+    pub energy_wh: i64,
+    pub cost: Money,
+}
+
+/// Someone who asked to use the dryer and is waiting for an admin
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct PendingUser {
+    pub telegram_id: i64,
+    pub chat_id: i64,
+    pub name: String,
+}
+
+/// What moved money in or out of a balance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionKind {
+    TopUp,
+    CycleCharge,
+    /// Fixing a mistake, either way
+    Adjustment,
+    /// Giving back what was charged, like for a cycle ruined by a fault
+    Refund,
+}
+
+impl TransactionKind {
+    /// Stable name, for storing
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKind::TopUp => "top_up",
+            TransactionKind::CycleCharge => "cycle_charge",
+            TransactionKind::Adjustment => "adjustment",
+            TransactionKind::Refund => "refund",
+        }
+    }
+
+    /// For the user
+    pub fn description(&self) -> &'static str {
+        match self {
+            TransactionKind::TopUp => "recarga",
+            TransactionKind::CycleCharge => "secagem",
+            TransactionKind::Adjustment => "ajuste",
+            TransactionKind::Refund => "reembolso",
+        }
+    }
+
+    /// Panics if `amount` has the wrong sign for this kind, that is a bug, not bad input
+    fn check_amount(&self, amount: Money) {
+        match self {
+            TransactionKind::TopUp | TransactionKind::Refund => {
+                assert!(amount > Money::ZERO, "{:?} must be positive", self)
+            }
+            TransactionKind::CycleCharge => {
+                assert!(amount < Money::ZERO, "{:?} must be negative", self)
             }
+            TransactionKind::Adjustment => {}
         }
     }
-    pub async fn get_db_id(&self, telegram_id: u64) -> Result<Option<User>, sqlx::Error> {
-        let telegram_id =
-            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
-        sqlx::query_as!(
-            User,
-            "select * from Users where telegram_id=$1",
-            telegram_id
-        )
-        .fetch_optional(&self.con)
-        .await
+}
+
+impl std::str::FromStr for TransactionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            TransactionKind::TopUp,
+            TransactionKind::CycleCharge,
+            TransactionKind::Adjustment,
+            TransactionKind::Refund,
+        ]
+        .into_iter()
+        .find(|kind| kind.as_str() == s)
+        .ok_or_else(|| format!("Unknown transaction kind `{}`", s))
     }
-    pub async fn update_user_balance(
+}
+
+/// One entry of a user ledger
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Transaction {
+    pub kind: String,
+    pub amount: Money,
+    pub balance_after: Money,
+    pub description: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Where users, balances and cycles are kept.
+///
+/// Implemented for Postgres and for SQLite, which keeps everything in a single file for small
+/// installs.
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_db_id(&self, telegram_id: u64) -> Result<Option<User>, sqlx::Error>;
+    /// By name
+    async fn list_users(&self) -> Result<Vec<User>, sqlx::Error>;
+    /// The updated user, `None` if there is no such user
+    async fn set_blocked(
+        &self,
+        telegram_id: u64,
+        blocked: bool,
+    ) -> Result<Option<User>, sqlx::Error>;
+    /// Asking again replaces the previous request
+    async fn request_registration(
+        &self,
+        telegram_id: u64,
+        chat_id: i64,
+        name: &str,
+    ) -> Result<(), sqlx::Error>;
+    /// Turns the request into a user with no balance, `None` if there was no request, like when
+    /// another admin already answered it
+    async fn approve_registration(
+        &self,
+        telegram_id: u64,
+    ) -> Result<Option<PendingUser>, sqlx::Error>;
+    /// Drops the request, `None` if there was none
+    async fn reject_registration(
+        &self,
+        telegram_id: u64,
+    ) -> Result<Option<PendingUser>, sqlx::Error>;
+    /// Appends to the user ledger and moves their balance by `amount` in one transaction,
+    /// returns the new balance. Nothing stops a balance from going negative, an overdraft is
+    /// recorded like anything else.
+    async fn record_transaction(
+        &self,
+        telegram_id: u64,
+        kind: TransactionKind,
+        amount: Money,
+        description: &str,
+    ) -> Result<Money, sqlx::Error>;
+    /// Most recent first, the charges of a cycle summed up as a single transaction, shown as of
+    /// the last one
+    async fn last_transactions(
         &self,
         telegram_id: u64,
-        value_to_discount_as_positive_number: f64,
-    ) -> Result<f64, sqlx::Error> {
-        assert!(
-            value_to_discount_as_positive_number >= 0.,
-            "Can't discount a positive amount"
-        );
-        let telegram_id =
-            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
-        let res: f64 = sqlx::query_scalar!(
-            "update users set balance_reais=GREATEST(0.0, balance_reais-$1) where telegram_id=$2 returning users.balance_reais;",
-            value_to_discount_as_positive_number,
-            telegram_id
-        )
-        .fetch_one(&self.con)
-        .await?;
-
-        Ok(res)
+        count: i64,
+    ) -> Result<Vec<Transaction>, sqlx::Error>;
+    async fn record_hardware_fault(&self, kind: &str, details: &str) -> Result<(), sqlx::Error>;
+    async fn get_setting(&self, key: &str) -> Result<Option<String>, sqlx::Error>;
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), sqlx::Error>;
+    /// Each machine has at most one active cycle, saving replaces the one of the same machine
+    async fn save_active_cycle(&self, cycle: &CycleStats) -> Result<(), sqlx::Error>;
+    /// Records a charge of `amount` for the cycle, like [`Storage::record_transaction`], and
+    /// saves the cycle with the meter reading it was billed up to, in one transaction. A restart
+    /// can then never bill the same energy twice nor miss what was billed. Returns the new
+    /// balance.
+    async fn charge_cycle(
+        &self,
+        cycle: &CycleStats,
+        amount: Money,
+        description: &str,
+    ) -> Result<Money, sqlx::Error>;
+    /// One per machine that had a cycle running
+    async fn load_active_cycles(&self) -> Result<Vec<ActiveCycle>, sqlx::Error>;
+    /// Moves the active cycle to the history, the saved active cycle of its machine is only
+    /// dropped if it is this one
+    async fn end_cycle(&self, cycle: &CycleStats, reason: EndReason) -> Result<(), sqlx::Error>;
+    /// Most recent first
+    async fn last_cycles(&self, telegram_id: u64, count: i64) -> Result<Vec<Cycle>, sqlx::Error>;
+    /// Most recent first
+    async fn monthly_usage(
+        &self,
+        telegram_id: u64,
+        months: i64,
+    ) -> Result<Vec<MonthlyUsage>, sqlx::Error>;
+}
+
+/// The storage picked by `DATABASE_URL`: `postgres://...` or `sqlite://PATH`
+pub struct Database {
+    storage: Box<dyn Storage>,
+}
+
+impl Database {
+    /// Schemas are created and kept up to date by the migrations embedded in the binary
+    pub async fn new() -> Self {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL not set");
+        let storage: Box<dyn Storage> = if url.starts_with("sqlite:") {
+            Box::new(SqliteStorage::connect(&url).await)
+        } else if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            Box::new(PostgresStorage::connect(&url).await)
+        } else {
+            panic!(
+                "DATABASE_URL must start with postgres:// or sqlite://, was: {}",
+                url
+            );
+        };
+        Self { storage }
+    }
+}
+
+impl Deref for Database {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.storage
     }
 }
@@ -0,0 +1,339 @@
+use super::{
+    ActiveCycle, Cycle, MonthlyUsage, PendingUser, Storage, Transaction, TransactionKind, User,
+};
+use crate::dryer_manager::{CycleStats, EndReason};
+use crate::money::Money;
+use chrono::{DateTime, Utc};
+use sqlx::migrate::Migrator;
+use sqlx::{PgExecutor, PgPool, Postgres};
+use std::time::Duration;
+
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/postgres");
+
+pub struct PostgresStorage {
+    con: PgPool,
+}
+
+impl PostgresStorage {
+    /// Waits for the database to be reachable, it may be starting along with us, and brings
+    /// its schema up to date
+    pub async fn connect(url: &str) -> Self {
+        let con = loop {
+            match PgPool::connect(url).await {
+                Ok(con) => break con,
+                Err(e) => {
+                    log::error!("{:#?}", e);
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+            }
+        };
+        MIGRATOR
+            .run(&con)
+            .await
+            .unwrap_or_else(|e| panic!("Error running database migrations: {}", e));
+        Self { con }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for PostgresStorage {
+    async fn get_db_id(&self, telegram_id: u64) -> Result<Option<User>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as!(
+            User,
+            r#"select telegram_id, name, (balance_reais * 100)::bigint as "balance!: Money", is_admin, blocked from users where telegram_id=$1"#,
+            telegram_id
+        )
+        .fetch_optional(&self.con)
+        .await
+    }
+    async fn list_users(&self) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as!(
+            User,
+            r#"select telegram_id, name, (balance_reais * 100)::bigint as "balance!: Money", is_admin, blocked from users order by name"#
+        )
+        .fetch_all(&self.con)
+        .await
+    }
+    async fn set_blocked(
+        &self,
+        telegram_id: u64,
+        blocked: bool,
+    ) -> Result<Option<User>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as!(
+            User,
+            r#"update users set blocked=$1 where telegram_id=$2 returning telegram_id, name, (balance_reais * 100)::bigint as "balance!: Money", is_admin, blocked"#,
+            blocked,
+            telegram_id
+        )
+        .fetch_optional(&self.con)
+        .await
+    }
+    async fn request_registration(
+        &self,
+        telegram_id: u64,
+        chat_id: i64,
+        name: &str,
+    ) -> Result<(), sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query!(
+            "insert into pending_users (telegram_id, chat_id, name, requested_at) values ($1, $2, $3, now())
+            on conflict (telegram_id) do update set chat_id=$2, name=$3, requested_at=now()",
+            telegram_id,
+            chat_id,
+            name
+        )
+        .execute(&self.con)
+        .await?;
+        Ok(())
+    }
+    async fn approve_registration(
+        &self,
+        telegram_id: u64,
+    ) -> Result<Option<PendingUser>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        let mut transaction = self.con.begin().await?;
+        let pending = sqlx::query_as!(
+            PendingUser,
+            "delete from pending_users where telegram_id=$1 returning telegram_id, chat_id, name",
+            telegram_id
+        )
+        .fetch_optional(&mut transaction)
+        .await?;
+        if let Some(pending) = &pending {
+            sqlx::query!(
+                "insert into users (telegram_id, name, balance_reais) values ($1, $2, 0) on conflict (telegram_id) do nothing",
+                pending.telegram_id,
+                pending.name
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(pending)
+    }
+    async fn reject_registration(
+        &self,
+        telegram_id: u64,
+    ) -> Result<Option<PendingUser>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as!(
+            PendingUser,
+            "delete from pending_users where telegram_id=$1 returning telegram_id, chat_id, name",
+            telegram_id
+        )
+        .fetch_optional(&self.con)
+        .await
+    }
+    async fn record_transaction(
+        &self,
+        telegram_id: u64,
+        kind: TransactionKind,
+        amount: Money,
+        description: &str,
+    ) -> Result<Money, sqlx::Error> {
+        let mut transaction = self.con.begin().await?;
+        let balance_after = append_to_ledger(
+            &mut transaction,
+            telegram_id,
+            kind,
+            amount,
+            description,
+            None,
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(balance_after)
+    }
+    async fn last_transactions(
+        &self,
+        telegram_id: u64,
+        count: i64,
+    ) -> Result<Vec<Transaction>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as!(
+            Transaction,
+            r#"select ledger.kind as "kind!", (grouped.amount_reais * 100)::bigint as "amount!: Money", (ledger.balance_after_reais * 100)::bigint as "balance_after!: Money", ledger.description as "description!", ledger.created_at as "created_at!" from (select max(id) as last_id, sum(amount_reais) as amount_reais from ledger where telegram_id=$1 group by kind, description, cycle_started_at, case when kind='cycle_charge' then null else id end order by last_id desc limit $2) grouped join ledger on ledger.id=grouped.last_id order by ledger.id desc"#,
+            telegram_id,
+            count
+        )
+        .fetch_all(&self.con)
+        .await
+    }
+    async fn record_hardware_fault(&self, kind: &str, details: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "insert into hardware_faults (kind, details) values ($1, $2)",
+            kind,
+            details
+        )
+        .execute(&self.con)
+        .await?;
+        Ok(())
+    }
+    async fn get_setting(&self, key: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar!("select value from settings where key=$1", key)
+            .fetch_optional(&self.con)
+            .await
+    }
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "insert into settings (key, value, updated_at) values ($1, $2, now())
+            on conflict (key) do update set value=$2, updated_at=now()",
+            key,
+            value
+        )
+        .execute(&self.con)
+        .await?;
+        Ok(())
+    }
+
+    async fn save_active_cycle(&self, cycle: &CycleStats) -> Result<(), sqlx::Error> {
+        save_active_cycle(&self.con, cycle).await
+    }
+    async fn charge_cycle(
+        &self,
+        cycle: &CycleStats,
+        amount: Money,
+        description: &str,
+    ) -> Result<Money, sqlx::Error> {
+        let mut transaction = self.con.begin().await?;
+        let balance_after = append_to_ledger(
+            &mut transaction,
+            cycle.user.telegram_id,
+            TransactionKind::CycleCharge,
+            -amount,
+            description,
+            Some(cycle.started_at),
+        )
+        .await?;
+        save_active_cycle(&mut transaction, cycle).await?;
+        transaction.commit().await?;
+        Ok(balance_after)
+    }
+    async fn load_active_cycles(&self) -> Result<Vec<ActiveCycle>, sqlx::Error> {
+        sqlx::query_as!(
+            ActiveCycle,
+            r#"select machine::bigint as "machine!", active_cycle.telegram_id as "telegram_id!", chat_id as "chat_id!", name as "name!", (balance_reais * 100)::bigint as "balance!: Money", started_at as "started_at!", start_meter_energy_wh as "start_meter_energy_wh!", last_meter_energy_wh as "last_meter_energy_wh!", consumed_wh as "consumed_wh!", carried_millicentavos as "carried_millicentavos!", (charged_reais * 100)::bigint as "charged!: Money", updated_at as "updated_at!"
+            from active_cycle join users on users.telegram_id = active_cycle.telegram_id order by machine"#
+        )
+        .fetch_all(&self.con)
+        .await
+    }
+    async fn end_cycle(&self, cycle: &CycleStats, reason: EndReason) -> Result<(), sqlx::Error> {
+        let telegram_id = i64::try_from(cycle.user.telegram_id)
+            .expect("Error converting telegram id from u64 to i64");
+        let mut transaction = self.con.begin().await?;
+        sqlx::query!(
+            "insert into cycles (telegram_id, started_at, ended_at, energy_wh, cost_reais, end_reason) values ($1, $2, now(), $3, $4::bigint/100.0, $5)",
+            telegram_id,
+            cycle.started_at,
+            i64::from(cycle.consumed_wh),
+            cycle.charged.centavos(),
+            reason.as_str()
+        )
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query!(
+            "delete from active_cycle where machine=$1 and started_at=$2",
+            i16::from(cycle.machine),
+            cycle.started_at
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await
+    }
+    async fn last_cycles(&self, telegram_id: u64, count: i64) -> Result<Vec<Cycle>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as!(
+            Cycle,
+            r#"select started_at, ended_at, energy_wh, (cost_reais * 100)::bigint as "cost!: Money", end_reason from cycles where telegram_id=$1 order by started_at desc limit $2"#,
+            telegram_id,
+            count
+        )
+        .fetch_all(&self.con)
+        .await
+    }
+    async fn monthly_usage(
+        &self,
+        telegram_id: u64,
+        months: i64,
+    ) -> Result<Vec<MonthlyUsage>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as!(
+            MonthlyUsage,
+            r#"select date_trunc('month', started_at) as "month!", count(*) as "cycles!", sum(energy_wh)::bigint as "energy_wh!", (sum(cost_reais) * 100)::bigint as "cost!: Money"
+            from cycles where telegram_id=$1 group by 1 order by 1 desc limit $2"#,
+            telegram_id,
+            months
+        )
+        .fetch_all(&self.con)
+        .await
+    }
+}
+
+/// Moves the balance by `amount` and appends that to the ledger, returns the new balance
+async fn append_to_ledger(
+    transaction: &mut sqlx::Transaction<'_, Postgres>,
+    telegram_id: u64,
+    kind: TransactionKind,
+    amount: Money,
+    description: &str,
+    cycle_started_at: Option<DateTime<Utc>>,
+) -> Result<Money, sqlx::Error> {
+    kind.check_amount(amount);
+    let telegram_id =
+        i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+    let balance_after = sqlx::query_scalar!(
+        r#"update users set balance_reais=balance_reais+$1::bigint/100.0 where telegram_id=$2 returning (balance_reais * 100)::bigint as "balance!: Money";"#,
+        amount.centavos(),
+        telegram_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        "insert into ledger (telegram_id, kind, amount_reais, balance_after_reais, description, cycle_started_at) values ($1, $2, $3::bigint/100.0, $4::bigint/100.0, $5, $6)",
+        telegram_id,
+        kind.as_str(),
+        amount.centavos(),
+        balance_after.centavos(),
+        description,
+        cycle_started_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+    Ok(balance_after)
+}
+
+async fn save_active_cycle<'c>(
+    executor: impl PgExecutor<'c>,
+    cycle: &CycleStats,
+) -> Result<(), sqlx::Error> {
+    let telegram_id = i64::try_from(cycle.user.telegram_id)
+        .expect("Error converting telegram id from u64 to i64");
+    sqlx::query!(
+        "insert into active_cycle (machine, telegram_id, chat_id, started_at, start_meter_energy_wh, last_meter_energy_wh, consumed_wh, carried_millicentavos, charged_reais, updated_at)
+        values ($9, $1, $2, $3, $4, $5, $6, $7, $8::bigint/100.0, now())
+        on conflict (machine) do update set telegram_id=$1, chat_id=$2, started_at=$3, start_meter_energy_wh=$4, last_meter_energy_wh=$5, consumed_wh=$6, carried_millicentavos=$7, charged_reais=$8::bigint/100.0, updated_at=now()",
+        telegram_id,
+        cycle.user.chat_id,
+        cycle.started_at,
+        i64::from(cycle.start_meter_energy_wh),
+        i64::from(cycle.last_meter_energy_wh),
+        i64::from(cycle.consumed_wh),
+        cycle.carried_millicentavos,
+        cycle.charged.centavos(),
+        i16::from(cycle.machine)
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,583 @@
+use super::{
+    ActiveCycle, Cycle, MonthlyUsage, PendingUser, Storage, Transaction, TransactionKind, User,
+};
+use crate::dryer_manager::{CycleStats, EndReason};
+use crate::money::Money;
+use chrono::{DateTime, Utc};
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{
+    Sqlite, SqliteConnectOptions, SqliteExecutor, SqliteJournalMode, SqlitePool, SqlitePoolOptions,
+};
+use std::str::FromStr;
+
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+
+/// Everything in a single file, for installs where running Postgres next to the bot is too
+/// much, like on the Pi driving the dryer.
+///
+/// Queries are checked at runtime only, the compile time checks run against Postgres.
+pub struct SqliteStorage {
+    con: SqlitePool,
+}
+
+impl SqliteStorage {
+    /// Creates the file if needed and brings its schema up to date, `sqlite::memory:` gives a
+    /// database that lives as long as this storage
+    pub async fn connect(url: &str) -> Self {
+        let options = SqliteConnectOptions::from_str(url)
+            .unwrap_or_else(|e| panic!("Invalid sqlite DATABASE_URL: {}", e))
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        // a single writer anyway, and an in memory database only exists in its connection
+        let con = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .unwrap_or_else(|e| panic!("Error opening sqlite database {}: {}", url, e));
+        MIGRATOR
+            .run(&con)
+            .await
+            .unwrap_or_else(|e| panic!("Error running database migrations: {}", e));
+        Self { con }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for SqliteStorage {
+    async fn get_db_id(&self, telegram_id: u64) -> Result<Option<User>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as(
+            "select telegram_id, name, balance_centavos as balance, is_admin, blocked from users where telegram_id=?",
+        )
+        .bind(telegram_id)
+        .fetch_optional(&self.con)
+        .await
+    }
+    async fn list_users(&self) -> Result<Vec<User>, sqlx::Error> {
+        sqlx::query_as(
+            "select telegram_id, name, balance_centavos as balance, is_admin, blocked from users order by name",
+        )
+        .fetch_all(&self.con)
+        .await
+    }
+    async fn set_blocked(
+        &self,
+        telegram_id: u64,
+        blocked: bool,
+    ) -> Result<Option<User>, sqlx::Error> {
+        sqlx::query("update users set blocked=? where telegram_id=?")
+            .bind(blocked)
+            .bind(i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64"))
+            .execute(&self.con)
+            .await?;
+        self.get_db_id(telegram_id).await
+    }
+    async fn request_registration(
+        &self,
+        telegram_id: u64,
+        chat_id: i64,
+        name: &str,
+    ) -> Result<(), sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query(
+            "insert or replace into pending_users (telegram_id, chat_id, name, requested_at) values (?, ?, ?, ?)",
+        )
+        .bind(telegram_id)
+        .bind(chat_id)
+        .bind(name)
+        .bind(Utc::now())
+        .execute(&self.con)
+        .await?;
+        Ok(())
+    }
+    async fn approve_registration(
+        &self,
+        telegram_id: u64,
+    ) -> Result<Option<PendingUser>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        let mut transaction = self.con.begin().await?;
+        let pending: Option<PendingUser> = sqlx::query_as(
+            "select telegram_id, chat_id, name from pending_users where telegram_id=?",
+        )
+        .bind(telegram_id)
+        .fetch_optional(&mut transaction)
+        .await?;
+        if let Some(pending) = &pending {
+            sqlx::query("delete from pending_users where telegram_id=?")
+                .bind(telegram_id)
+                .execute(&mut transaction)
+                .await?;
+            sqlx::query("insert or ignore into users (telegram_id, name) values (?, ?)")
+                .bind(pending.telegram_id)
+                .bind(&pending.name)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(pending)
+    }
+    async fn reject_registration(
+        &self,
+        telegram_id: u64,
+    ) -> Result<Option<PendingUser>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        let mut transaction = self.con.begin().await?;
+        let pending: Option<PendingUser> = sqlx::query_as(
+            "select telegram_id, chat_id, name from pending_users where telegram_id=?",
+        )
+        .bind(telegram_id)
+        .fetch_optional(&mut transaction)
+        .await?;
+        sqlx::query("delete from pending_users where telegram_id=?")
+            .bind(telegram_id)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(pending)
+    }
+    async fn record_transaction(
+        &self,
+        telegram_id: u64,
+        kind: TransactionKind,
+        amount: Money,
+        description: &str,
+    ) -> Result<Money, sqlx::Error> {
+        let mut transaction = self.con.begin().await?;
+        let balance_after = append_to_ledger(
+            &mut transaction,
+            telegram_id,
+            kind,
+            amount,
+            description,
+            None,
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(balance_after)
+    }
+    async fn last_transactions(
+        &self,
+        telegram_id: u64,
+        count: i64,
+    ) -> Result<Vec<Transaction>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as(
+            "select ledger.kind, grouped.amount, ledger.balance_after_centavos as balance_after, ledger.description, ledger.created_at from (select max(id) as last_id, sum(amount_centavos) as amount from ledger where telegram_id=? group by kind, description, cycle_started_at, case when kind='cycle_charge' then null else id end order by last_id desc limit ?) grouped join ledger on ledger.id=grouped.last_id order by ledger.id desc",
+        )
+        .bind(telegram_id)
+        .bind(count)
+        .fetch_all(&self.con)
+        .await
+    }
+    async fn record_hardware_fault(&self, kind: &str, details: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("insert into hardware_faults (occurred_at, kind, details) values (?, ?, ?)")
+            .bind(Utc::now())
+            .bind(kind)
+            .bind(details)
+            .execute(&self.con)
+            .await?;
+        Ok(())
+    }
+    async fn get_setting(&self, key: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("select value from settings where key=?")
+            .bind(key)
+            .fetch_optional(&self.con)
+            .await
+    }
+    async fn set_setting(&self, key: &str, value: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("insert or replace into settings (key, value, updated_at) values (?, ?, ?)")
+            .bind(key)
+            .bind(value)
+            .bind(Utc::now())
+            .execute(&self.con)
+            .await?;
+        Ok(())
+    }
+    async fn save_active_cycle(&self, cycle: &CycleStats) -> Result<(), sqlx::Error> {
+        save_active_cycle(&self.con, cycle).await
+    }
+    async fn charge_cycle(
+        &self,
+        cycle: &CycleStats,
+        amount: Money,
+        description: &str,
+    ) -> Result<Money, sqlx::Error> {
+        let mut transaction = self.con.begin().await?;
+        let balance_after = append_to_ledger(
+            &mut transaction,
+            cycle.user.telegram_id,
+            TransactionKind::CycleCharge,
+            -amount,
+            description,
+            Some(cycle.started_at),
+        )
+        .await?;
+        save_active_cycle(&mut transaction, cycle).await?;
+        transaction.commit().await?;
+        Ok(balance_after)
+    }
+    async fn load_active_cycles(&self) -> Result<Vec<ActiveCycle>, sqlx::Error> {
+        sqlx::query_as(
+            "select machine, active_cycle.telegram_id, chat_id, name, balance_centavos as balance, started_at, start_meter_energy_wh, last_meter_energy_wh, consumed_wh, carried_millicentavos, charged_centavos as charged, updated_at
+            from active_cycle join users on users.telegram_id = active_cycle.telegram_id order by machine",
+        )
+        .fetch_all(&self.con)
+        .await
+    }
+    async fn end_cycle(&self, cycle: &CycleStats, reason: EndReason) -> Result<(), sqlx::Error> {
+        let telegram_id = i64::try_from(cycle.user.telegram_id)
+            .expect("Error converting telegram id from u64 to i64");
+        let mut transaction = self.con.begin().await?;
+        sqlx::query(
+            "insert into cycles (telegram_id, started_at, ended_at, energy_wh, cost_centavos, end_reason) values (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(telegram_id)
+        .bind(cycle.started_at)
+        .bind(Utc::now())
+        .bind(i64::from(cycle.consumed_wh))
+        .bind(cycle.charged)
+        .bind(reason.as_str())
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query("delete from active_cycle where machine=? and started_at=?")
+            .bind(i64::from(cycle.machine))
+            .bind(cycle.started_at)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await
+    }
+    async fn last_cycles(&self, telegram_id: u64, count: i64) -> Result<Vec<Cycle>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as(
+            "select started_at, ended_at, energy_wh, cost_centavos as cost, end_reason from cycles where telegram_id=? order by started_at desc limit ?",
+        )
+        .bind(telegram_id)
+        .bind(count)
+        .fetch_all(&self.con)
+        .await
+    }
+    async fn monthly_usage(
+        &self,
+        telegram_id: u64,
+        months: i64,
+    ) -> Result<Vec<MonthlyUsage>, sqlx::Error> {
+        let telegram_id =
+            i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+        sqlx::query_as(
+            "select strftime('%Y-%m-01 00:00:00', started_at) as month, count(*) as cycles, sum(energy_wh) as energy_wh, sum(cost_centavos) as cost
+            from cycles where telegram_id=? group by 1 order by 1 desc limit ?",
+        )
+        .bind(telegram_id)
+        .bind(months)
+        .fetch_all(&self.con)
+        .await
+    }
+}
+
+/// Moves the balance by `amount` and appends that to the ledger, returns the new balance
+async fn append_to_ledger(
+    transaction: &mut sqlx::Transaction<'_, Sqlite>,
+    telegram_id: u64,
+    kind: TransactionKind,
+    amount: Money,
+    description: &str,
+    cycle_started_at: Option<DateTime<Utc>>,
+) -> Result<Money, sqlx::Error> {
+    kind.check_amount(amount);
+    let telegram_id =
+        i64::try_from(telegram_id).expect("Error converting telegram id from u64 to i64");
+    sqlx::query("update users set balance_centavos=balance_centavos+? where telegram_id=?")
+        .bind(amount)
+        .bind(telegram_id)
+        .execute(&mut *transaction)
+        .await?;
+    let balance_after: Money =
+        sqlx::query_scalar("select balance_centavos from users where telegram_id=?")
+            .bind(telegram_id)
+            .fetch_one(&mut *transaction)
+            .await?;
+    sqlx::query(
+        "insert into ledger (telegram_id, kind, amount_centavos, balance_after_centavos, description, created_at, cycle_started_at) values (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(telegram_id)
+    .bind(kind.as_str())
+    .bind(amount)
+    .bind(balance_after)
+    .bind(description)
+    .bind(Utc::now())
+    .bind(cycle_started_at)
+    .execute(&mut *transaction)
+    .await?;
+    Ok(balance_after)
+}
+
+async fn save_active_cycle<'c>(
+    executor: impl SqliteExecutor<'c>,
+    cycle: &CycleStats,
+) -> Result<(), sqlx::Error> {
+    let telegram_id = i64::try_from(cycle.user.telegram_id)
+        .expect("Error converting telegram id from u64 to i64");
+    sqlx::query(
+        "insert or replace into active_cycle (machine, telegram_id, chat_id, started_at, start_meter_energy_wh, last_meter_energy_wh, consumed_wh, carried_millicentavos, charged_centavos, updated_at)
+        values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(i64::from(cycle.machine))
+    .bind(telegram_id)
+    .bind(cycle.user.chat_id)
+    .bind(cycle.started_at)
+    .bind(i64::from(cycle.start_meter_energy_wh))
+    .bind(i64::from(cycle.last_meter_energy_wh))
+    .bind(i64::from(cycle.consumed_wh))
+    .bind(cycle.carried_millicentavos)
+    .bind(cycle.charged)
+    .bind(Utc::now())
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// In memory storage with a registered user, id 42 in chat 420
+#[cfg(test)]
+async fn storage_with_user() -> SqliteStorage {
+    let storage = SqliteStorage::connect("sqlite::memory:").await;
+    storage
+        .request_registration(42, 420, "Fulano")
+        .await
+        .unwrap();
+    storage.approve_registration(42).await.unwrap();
+    storage
+}
+
+/// Cycle of the user in [`storage_with_user`] just started on machine 1, with 5 reais, the meter
+/// at 1000Wh
+#[cfg(test)]
+fn cycle_started_at(started_at: chrono::DateTime<Utc>) -> ActiveCycle {
+    ActiveCycle {
+        machine: 1,
+        telegram_id: 42,
+        chat_id: 420,
+        name: "Fulano".to_string(),
+        balance: Money::from_centavos(500),
+        started_at,
+        start_meter_energy_wh: 1000,
+        last_meter_energy_wh: 1000,
+        consumed_wh: 0,
+        carried_millicentavos: 0,
+        charged: Money::ZERO,
+        updated_at: started_at,
+    }
+}
+
+#[tokio::test]
+async fn registrations_are_approved_once() {
+    let storage = SqliteStorage::connect("sqlite::memory:").await;
+    storage
+        .request_registration(42, 420, "Fulano")
+        .await
+        .unwrap();
+    assert!(storage.get_db_id(42).await.unwrap().is_none());
+    let approved = storage.approve_registration(42).await.unwrap().unwrap();
+    assert_eq!(approved.chat_id, 420);
+    assert!(storage.approve_registration(42).await.unwrap().is_none());
+    assert_eq!(
+        storage.get_db_id(42).await.unwrap().unwrap().balance,
+        Money::ZERO
+    );
+    storage
+        .request_registration(43, 430, "Ciclano")
+        .await
+        .unwrap();
+    assert!(storage.reject_registration(43).await.unwrap().is_some());
+    assert!(storage.approve_registration(43).await.unwrap().is_none());
+    assert_eq!(storage.list_users().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn users_are_blocked_and_unblocked() {
+    let storage = storage_with_user().await;
+    assert!(
+        storage
+            .set_blocked(42, true)
+            .await
+            .unwrap()
+            .unwrap()
+            .blocked
+    );
+    assert!(storage.get_db_id(42).await.unwrap().unwrap().blocked);
+    assert!(
+        !storage
+            .set_blocked(42, false)
+            .await
+            .unwrap()
+            .unwrap()
+            .blocked
+    );
+    assert!(storage.set_blocked(43, true).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn settings_are_replaced() {
+    let storage = SqliteStorage::connect("sqlite::memory:").await;
+    assert!(storage.get_setting("price").await.unwrap().is_none());
+    storage.set_setting("price", "110").await.unwrap();
+    storage.set_setting("price", "120").await.unwrap();
+    assert_eq!(
+        storage.get_setting("price").await.unwrap().as_deref(),
+        Some("120")
+    );
+}
+
+#[tokio::test]
+async fn transactions_move_the_balance() {
+    let storage = storage_with_user().await;
+    let balance = storage
+        .record_transaction(
+            42,
+            TransactionKind::TopUp,
+            Money::from_centavos(1000),
+            "Pix",
+        )
+        .await
+        .unwrap();
+    assert_eq!(balance, Money::from_centavos(1000));
+    let balance = storage
+        .record_transaction(
+            42,
+            TransactionKind::CycleCharge,
+            Money::from_centavos(-3),
+            "Ciclo",
+        )
+        .await
+        .unwrap();
+    assert_eq!(balance, Money::from_centavos(997));
+    assert_eq!(
+        storage.get_db_id(42).await.unwrap().unwrap().balance,
+        Money::from_centavos(997)
+    );
+    let transactions = storage.last_transactions(42, 10).await.unwrap();
+    assert_eq!(transactions.len(), 2);
+    assert_eq!(transactions[0].kind, "cycle_charge");
+    assert_eq!(transactions[1].balance_after, Money::from_centavos(1000));
+}
+
+#[tokio::test]
+async fn ended_cycles_go_to_the_history() {
+    use chrono::{Duration, TimeZone};
+    let storage = storage_with_user().await;
+    let started_at = Utc.ymd(2022, 3, 10).and_hms(12, 0, 0);
+    let cycle = CycleStats::restore(ActiveCycle {
+        last_meter_energy_wh: 1500,
+        consumed_wh: 500,
+        carried_millicentavos: 250,
+        charged: Money::from_centavos(55),
+        updated_at: started_at + Duration::minutes(30),
+        ..cycle_started_at(started_at)
+    });
+    storage.save_active_cycle(&cycle).await.unwrap();
+    let saved = storage.load_active_cycles().await.unwrap().pop().unwrap();
+    assert_eq!(saved.started_at, started_at);
+    assert_eq!(saved.carried_millicentavos, 250);
+    assert_eq!(saved.charged, Money::from_centavos(55));
+    storage.end_cycle(&cycle, EndReason::Idle).await.unwrap();
+    assert!(storage.load_active_cycles().await.unwrap().is_empty());
+    let cycles = storage.last_cycles(42, 5).await.unwrap();
+    assert_eq!(cycles.len(), 1);
+    assert_eq!(cycles[0].energy_wh, 500);
+    assert_eq!(cycles[0].end_reason, "idle");
+    let months = storage.monthly_usage(42, 6).await.unwrap();
+    assert_eq!(months.len(), 1);
+    assert_eq!(months[0].month, Utc.ymd(2022, 3, 1).and_hms(0, 0, 0));
+    assert_eq!(months[0].cost, Money::from_centavos(55));
+}
+
+#[tokio::test]
+async fn cycle_charges_save_the_cycle() {
+    use chrono::TimeZone;
+    let storage = storage_with_user().await;
+    let started_at = Utc.ymd(2022, 3, 10).and_hms(12, 0, 0);
+    let cycle = CycleStats::restore(ActiveCycle {
+        balance: Money::from_centavos(100),
+        last_meter_energy_wh: 1100,
+        consumed_wh: 100,
+        charged: Money::from_centavos(11),
+        ..cycle_started_at(started_at)
+    });
+    let balance = storage
+        .charge_cycle(&cycle, Money::from_centavos(11), "Ciclo")
+        .await
+        .unwrap();
+    assert_eq!(balance, Money::from_centavos(-11));
+    let saved = storage.load_active_cycles().await.unwrap().pop().unwrap();
+    assert_eq!(saved.last_meter_energy_wh, 1100);
+    assert_eq!(saved.charged, Money::from_centavos(11));
+    let other = CycleStats::restore(ActiveCycle {
+        started_at: started_at - chrono::Duration::hours(1),
+        ..saved
+    });
+    storage.end_cycle(&other, EndReason::Idle).await.unwrap();
+    assert_eq!(storage.load_active_cycles().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn each_machine_has_its_own_active_cycle() {
+    use chrono::TimeZone;
+    let storage = storage_with_user().await;
+    let started_at = Utc.ymd(2022, 4, 1).and_hms(12, 0, 0);
+    let first = CycleStats::restore(cycle_started_at(started_at));
+    let second = CycleStats::restore(ActiveCycle {
+        machine: 2,
+        ..cycle_started_at(started_at)
+    });
+    storage.save_active_cycle(&first).await.unwrap();
+    storage.save_active_cycle(&second).await.unwrap();
+    let machines: Vec<_> = storage
+        .load_active_cycles()
+        .await
+        .unwrap()
+        .iter()
+        .map(|cycle| cycle.machine)
+        .collect();
+    assert_eq!(machines, [1, 2]);
+    storage.end_cycle(&first, EndReason::Idle).await.unwrap();
+    let left = storage.load_active_cycles().await.unwrap();
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].machine, 2);
+}
+
+#[tokio::test]
+async fn cycle_charges_are_one_transaction() {
+    use chrono::TimeZone;
+    let storage = storage_with_user().await;
+    storage
+        .record_transaction(42, TransactionKind::TopUp, Money::from_centavos(500), "Pix")
+        .await
+        .unwrap();
+    for started_at in [
+        Utc.ymd(2022, 3, 10).and_hms(12, 0, 0),
+        Utc.ymd(2022, 3, 11).and_hms(12, 0, 0),
+    ] {
+        let cycle = CycleStats::restore(cycle_started_at(started_at));
+        for _ in 0..3 {
+            storage
+                .charge_cycle(&cycle, Money::from_centavos(1), "Ciclo")
+                .await
+                .unwrap();
+        }
+    }
+    storage
+        .record_transaction(42, TransactionKind::TopUp, Money::from_centavos(500), "Pix")
+        .await
+        .unwrap();
+    let transactions = storage.last_transactions(42, 3).await.unwrap();
+    let amounts: Vec<_> = transactions.iter().map(|t| t.amount.centavos()).collect();
+    assert_eq!(amounts, [500, -3, -3]);
+    assert_eq!(transactions[1].balance_after, Money::from_centavos(494));
+    assert_eq!(transactions[2].balance_after, Money::from_centavos(497));
+    assert_eq!(storage.last_transactions(42, 10).await.unwrap().len(), 4);
+}
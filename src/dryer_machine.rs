@@ -1,58 +1,406 @@
-use crate::dryer_machine::energy_switch::EnergySwitch;
-use std::time::Duration;
+use crate::dryer_machine::energy_switch::{EnergySwitch, SwitchConfig};
+use dringos::error::Error;
+use dringos::meter::{AsyncMeter, Data, EnergyMeter, PowerStats, Sampler};
+use dringos::relay::{self, AsyncRelay, Relay, SmartPlug};
+use dringos::serial::{PortLocator, ReconnectingPort};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const DEFAULT_PZEM_PORT: &str = "/dev/ttyUSB0";
+/// How often the meter is polled in the background
+const SAMPLE_PERIOD: Duration = Duration::from_millis(500);
+/// Samples kept around, 5 minutes worth of them
+const SAMPLE_CAPACITY: usize = 600;
+/// Samples older than this are not trusted, the sampler is probably failing
+pub const MAX_SAMPLE_AGE: Duration = Duration::from_millis(1500);
+/// Attempts at reading the meter directly before giving up on a transient error
+const METER_READ_ATTEMPTS: u32 = 3;
+const METER_READ_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Attempts at opening the relay before giving up, leaving it to the hardware checks
+const RELAY_OFF_ATTEMPTS: u32 = 3;
+/// Readings right after switching may still show the previous state
+const FAULT_SETTLE_TIME: Duration = Duration::from_secs(3);
+/// A fault is only raised when every reading over this window shows it
+const FAULT_WINDOW: Duration = Duration::from_secs(5);
+const MIN_FAULT_SAMPLES: usize = 3;
+/// More than what the meter reads with nothing plugged, less than the dryer drum motor alone
+const MAX_POWER_WHILE_OFF_W: f32 = 10.;
+const MIN_VOLTAGE_WHILE_ON: f32 = 90.;
+
+/// The relay and the meter disagree
+#[derive(Debug, Clone, PartialEq)]
+pub enum HardwareFault {
+    /// The relay is probably welded or shorted, people can dry for free
+    PowerWhileOff { power_w: f32 },
+    /// The relay, the meter wiring or the mains itself is down
+    NoVoltageWhileOn { voltage: f32 },
+    /// The relay refused to open, the dryer may still be powered
+    RelayNotOff { error: String },
+}
+
+impl HardwareFault {
+    /// Stable name, for storing
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HardwareFault::PowerWhileOff { .. } => "power_while_off",
+            HardwareFault::NoVoltageWhileOn { .. } => "no_voltage_while_on",
+            HardwareFault::RelayNotOff { .. } => "relay_not_off",
+        }
+    }
+}
+
+impl Display for HardwareFault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HardwareFault::PowerWhileOff { power_w } => write!(
+                f,
+                "Secadora consumindo {:.1}W com o relé desligado, ele pode estar em curto",
+                power_w
+            ),
+            HardwareFault::NoVoltageWhileOn { voltage } => write!(
+                f,
+                "Medidor lendo {:.1}V com o relé ligado, verifique o relé, o medidor e a rede",
+                voltage
+            ),
+            HardwareFault::RelayNotOff { error } => write!(
+                f,
+                "O relé não desligou ({}), a secadora pode continuar ligada",
+                error
+            ),
+        }
+    }
+}
+
+/// `fault` for the latest reading if every reading of the window after the settle time shows it
+fn check_readings(
+    pzem: &Sampler,
+    switched_at: Instant,
+    fault: impl Fn(&Data) -> Option<HardwareFault>,
+) -> Option<HardwareFault> {
+    if switched_at.elapsed() < FAULT_SETTLE_TIME + FAULT_WINDOW {
+        return None;
+    }
+    let since = (switched_at + FAULT_SETTLE_TIME).max(Instant::now() - FAULT_WINDOW);
+    let samples = pzem.samples_since(since);
+    if samples.len() < MIN_FAULT_SAMPLES {
+        return None;
+    }
+    let faults: Option<Vec<HardwareFault>> = samples.iter().map(|s| fault(&s.data)).collect();
+    faults?.pop()
+}
 
 mod energy_switch;
 
+/// What powers a machine on and off
 #[derive(Debug)]
+enum RelayConfig {
+    SmartPlug(SmartPlug),
+    Switch(SwitchConfig),
+}
+
+/// The meter and the relay of each machine, paired in order: one entry of `pzem_ports` and one
+/// of `smart_plugs`, or of `switches` if there are no smart plugs, per machine
+fn machine_configs(
+    pzem_ports: Option<&str>,
+    smart_plugs: Option<&str>,
+    switches: Option<&str>,
+) -> Result<Vec<(PortLocator, RelayConfig)>, String> {
+    let ports: Vec<PortLocator> = parse_list(pzem_ports.unwrap_or(DEFAULT_PZEM_PORT), "PZEM_PORT")?;
+    let relays: Vec<RelayConfig> = match (smart_plugs, switches) {
+        (Some(plugs), _) => parse_list(plugs, "DRYER_SMART_PLUG")?
+            .into_iter()
+            .map(RelayConfig::SmartPlug)
+            .collect(),
+        (None, Some(switches)) => parse_list(switches, "DRYER_SWITCH")?
+            .into_iter()
+            .map(RelayConfig::Switch)
+            .collect(),
+        (None, None) => vec![RelayConfig::Switch(SwitchConfig::default())],
+    };
+    if ports.len() != relays.len() {
+        return Err(format!(
+            "{} meters in PZEM_PORT but {} relays, each machine needs one of each",
+            ports.len(),
+            relays.len()
+        ));
+    }
+    Ok(ports.into_iter().zip(relays).collect())
+}
+
+/// The comma separated entries of `variable`
+fn parse_list<T: FromStr<Err = String>>(list: &str, variable: &str) -> Result<Vec<T>, String> {
+    list.split(',')
+        .map(|entry| {
+            entry
+                .trim()
+                .parse()
+                .map_err(|e| format!("Invalid {}: {}", variable, e))
+        })
+        .collect()
+}
+
 pub struct OffState {
-    pzem: dringos::pzemv3::Pzem,
-    switch: energy_switch::EnergySwitch,
+    pzem: Sampler,
+    switch: AsyncRelay,
+    switched_at: Instant,
 }
 
 impl OffState {
-    pub fn new() -> OffState {
-        let usb_port = "/dev/ttyUSB0";
-        let port = serialport::new(usb_port, 9600)
-            .timeout(Duration::from_millis(200))
-            .open()
-            .map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Cant connect to usb: {}", e.to_string()),
-                )
+    /// One machine per meter port in `PZEM_PORT`, comma separated, each a path, `by-id:NAME` or
+    /// `usb:VID:PID[:SERIAL]`. Ports don't need to be there yet, they are (re)opened whenever
+    /// they show up.
+    /// The relays are paired with the meters in order, from the comma separated smart plugs in
+    /// `DRYER_SMART_PLUG` if set (see [`SmartPlug`]), otherwise the GPIO lines in `DRYER_SWITCH`
+    /// (see [`SwitchConfig`]).
+    pub fn machines() -> Vec<Result<OffState, (OffState, relay::Error)>> {
+        let var = |name| std::env::var(name).ok();
+        let configs = machine_configs(
+            var("PZEM_PORT").as_deref(),
+            var("DRYER_SMART_PLUG").as_deref(),
+            var("DRYER_SWITCH").as_deref(),
+        )
+        .unwrap_or_else(|e| panic!("{}", e));
+        configs
+            .into_iter()
+            .map(|(locator, relay)| {
+                let port = ReconnectingPort::new(locator, 9600, Duration::from_millis(200));
+                let pzem = dringos::pzemv3::Pzem::new(port);
+                match relay {
+                    RelayConfig::SmartPlug(plug) => Self::with_meter(pzem, plug),
+                    RelayConfig::Switch(config) => {
+                        Self::with_meter(pzem, EnergySwitch::new(&config))
+                    }
+                }
             })
-            .unwrap_or_else(|e| panic!("Cannot open `{}`: {}.", usb_port, e));
-        let pzem = dringos::pzemv3::Pzem::new(port);
-        let mut switch = EnergySwitch::new();
-        switch.turn_off();
-        Self { pzem, switch }
+            .collect()
+    }
+
+    /// The meter and the relay are moved to their own I/O threads so using them never blocks
+    /// the runtime, and the meter is sampled in the background from then on, whether the dryer
+    /// is on or off. The relay is opened first, if it refuses the machine is still built, like
+    /// when [`OnState::turn_off`] fails, so the caller can keep trying.
+    pub fn with_meter<M: EnergyMeter + Send + 'static, R: Relay + Send + 'static>(
+        pzem: M,
+        mut switch: R,
+    ) -> Result<OffState, (OffState, relay::Error)> {
+        // blocks, but only once on startup, before there is anything else to do
+        let turned_off = switch.turn_off();
+        let off = Self {
+            pzem: Sampler::spawn(AsyncMeter::spawn(pzem), SAMPLE_PERIOD, SAMPLE_CAPACITY),
+            switch: AsyncRelay::spawn(switch),
+            switched_at: Instant::now(),
+        };
+        match turned_off {
+            Ok(()) => Ok(off),
+            Err(e) => Err((off, e)),
+        }
     }
 
-    pub fn turn_on(mut self) -> OnState {
-        self.switch.turn_on();
-        OnState {
+    /// Stays off if the relay refuses to turn on
+    pub async fn turn_on(mut self) -> Result<OnState, (OffState, relay::Error)> {
+        if let Err(e) = self.switch.turn_on().await {
+            // whatever state it was left in, it must not be on
+            if let Err(e) = self.switch.turn_off().await {
+                log::error!("Error setting dryer off after failing to turn it on: {}", e);
+            }
+            self.switched_at = Instant::now();
+            return Err((self, e));
+        }
+        Ok(OnState {
             pzem: self.pzem,
             switch: self.switch,
-        }
+            switched_at: Instant::now(),
+        })
+    }
+
+    /// Tries opening the relay again, for when turning off failed
+    pub async fn turn_off(&mut self) -> Result<(), relay::Error> {
+        self.switch.turn_off().await?;
+        self.switched_at = Instant::now();
+        Ok(())
+    }
+
+    /// Goes to the meter right away, like [`OnState::read_fresh_data`], for the final reading of
+    /// a cycle that was closed without one
+    pub async fn read_fresh_data(&mut self) -> Result<Data, Error> {
+        read_fresh_data(&self.pzem).await
+    }
+
+    /// Power flowing through a relay that should be open
+    pub fn check_hardware(&self) -> Option<HardwareFault> {
+        check_readings(&self.pzem, self.switched_at, |data| {
+            (data.power_w > MAX_POWER_WHILE_OFF_W).then_some(HardwareFault::PowerWhileOff {
+                power_w: data.power_w,
+            })
+        })
     }
 }
 
 impl OnState {
-    pub fn get_current_power(&mut self) -> f32 {
-        let data = self.pzem.read_data().expect("Error reading pzem data!");
-        data.power_w
+    pub async fn get_current_power(&mut self) -> Result<f32, Error> {
+        Ok(self.read_data().await?.power_w)
     }
-    pub fn turn_off(mut self) -> OffState {
-        self.switch.turn_off();
-        OffState {
+    /// Latest background sample, only goes to the meter if there is no recent one
+    pub async fn read_data(&mut self) -> Result<Data, Error> {
+        match self.pzem.latest() {
+            Some(sample) if sample.taken_at.elapsed() <= MAX_SAMPLE_AGE => Ok(sample.data),
+            _ => self.read_fresh_data().await,
+        }
+    }
+    /// Goes to the meter right away, for when the next sample may be too late.
+    ///
+    /// Transient errors, like line noise, are retried a few times before giving up.
+    pub async fn read_fresh_data(&mut self) -> Result<Data, Error> {
+        read_fresh_data(&self.pzem).await
+    }
+    pub fn power_stats(&self, window: Duration) -> Option<PowerStats> {
+        self.pzem.power_stats(window)
+    }
+    /// Retries a few times. There is no going back to on if the relay keeps failing, the error
+    /// comes with the off state so it can be retried from there.
+    pub async fn turn_off(self) -> Result<OffState, (OffState, relay::Error)> {
+        let mut attempt = 1;
+        let result = loop {
+            match self.switch.turn_off().await {
+                Ok(()) => break Ok(()),
+                Err(e) if attempt < RELAY_OFF_ATTEMPTS => {
+                    log::error!("Error setting dryer off (attempt {}): {}", attempt, e);
+                    attempt += 1;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        let off = OffState {
             pzem: self.pzem,
             switch: self.switch,
+            switched_at: Instant::now(),
+        };
+        match result {
+            Ok(()) => Ok(off),
+            Err(e) => Err((off, e)),
         }
     }
+
+    /// No voltage after a relay that should be closed
+    pub fn check_hardware(&self) -> Option<HardwareFault> {
+        check_readings(&self.pzem, self.switched_at, |data| {
+            (data.voltage < MIN_VOLTAGE_WHILE_ON).then_some(HardwareFault::NoVoltageWhileOn {
+                voltage: data.voltage,
+            })
+        })
+    }
 }
 
-#[derive(Debug)]
 pub struct OnState {
-    pzem: dringos::pzemv3::Pzem,
-    switch: energy_switch::EnergySwitch,
+    pzem: Sampler,
+    switch: AsyncRelay,
+    switched_at: Instant,
+}
+
+/// Transient errors, like line noise, are retried a few times before giving up
+async fn read_fresh_data(pzem: &Sampler) -> Result<Data, Error> {
+    let mut attempt = 1;
+    loop {
+        match pzem.meter().read_data().await {
+            Err(e) if e.is_retryable() && attempt < METER_READ_ATTEMPTS => {
+                log::warn!("Error reading pzem data (attempt {}): {}", attempt, e);
+                attempt += 1;
+                tokio::time::sleep(METER_READ_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[tokio::test]
+async fn relay_follows_the_state_machine() {
+    use dringos::emulator::Emulator;
+    use dringos::relay::{MockRelay, RelayState};
+    let emulator = Emulator::with_manual_clock();
+    emulator.set_energy_wh(1234);
+    let relay = MockRelay::new();
+    let off = OffState::with_meter(dringos::pzemv3::Pzem::new(emulator.port()), relay.clone())
+        .map_err(|(_, e)| e)
+        .unwrap();
+    relay.set_failing(true);
+    let (off, _) = off.turn_on().await.map(|_| ()).unwrap_err();
+    assert_eq!(relay.current_state(), RelayState::Off);
+    relay.set_failing(false);
+    let mut on = off.turn_on().await.map_err(|(_, e)| e).unwrap();
+    assert_eq!(relay.current_state(), RelayState::On);
+    assert_eq!(on.read_fresh_data().await.unwrap().energy_wh, 1234);
+    let off = on.turn_off().await.map_err(|(_, e)| e).unwrap();
+    assert_eq!(relay.current_state(), RelayState::Off);
+    let on = off.turn_on().await.map_err(|(_, e)| e).unwrap();
+    relay.set_failing(true);
+    let (mut off, _) = on.turn_off().await.map(|_| ()).unwrap_err();
+    assert_eq!(relay.current_state(), RelayState::On);
+    relay.set_failing(false);
+    off.turn_off().await.unwrap();
+    assert_eq!(relay.current_state(), RelayState::Off);
+    emulator.set_energy_wh(1240);
+    assert_eq!(off.read_fresh_data().await.unwrap().energy_wh, 1240);
+}
+
+#[tokio::test(start_paused = true)]
+async fn power_while_off_is_a_hardware_fault() {
+    use dringos::emulator::Emulator;
+    use dringos::relay::MockRelay;
+    let emulator = Emulator::with_manual_clock();
+    emulator.set_power_w(1500.);
+    let mut off = OffState::with_meter(
+        dringos::pzemv3::Pzem::new(emulator.port()),
+        MockRelay::new(),
+    )
+    .map_err(|(_, e)| e)
+    .unwrap();
+    // time is paused, sleeping only lets the sampler poll the meter
+    while off.pzem.samples_since(off.switched_at).len() < MIN_FAULT_SAMPLES {
+        tokio::time::sleep(SAMPLE_PERIOD).await;
+    }
+    // just switched, readings can't be trusted yet
+    assert_eq!(off.check_hardware(), None);
+    off.switched_at -= FAULT_SETTLE_TIME + FAULT_WINDOW;
+    assert_eq!(
+        off.check_hardware(),
+        Some(HardwareFault::PowerWhileOff { power_w: 1500. })
+    );
+    emulator.set_power_w(0.);
+    while off.pzem.latest().map(|sample| sample.data.power_w) != Some(0.) {
+        tokio::time::sleep(SAMPLE_PERIOD).await;
+    }
+    assert_eq!(off.check_hardware(), None);
+}
+
+#[test]
+fn machines_pair_meters_with_relays() {
+    let configs = machine_configs(
+        Some("usb:1a86:7523:A, usb:1a86:7523:B"),
+        None,
+        Some("/dev/gpiochip0:26,/dev/gpiochip0:19:active-low:dryer-2"),
+    )
+    .unwrap();
+    assert_eq!(configs.len(), 2);
+    assert!(matches!(
+        &configs[1],
+        (PortLocator::UsbId { serial_number: Some(serial), .. }, RelayConfig::Switch(switch))
+            if serial == "B" && switch.line == 19 && switch.active_low
+    ));
+    let configs = machine_configs(None, None, None).unwrap();
+    assert!(matches!(
+        &configs[..],
+        [(PortLocator::Path(_), RelayConfig::Switch(switch))] if *switch == SwitchConfig::default()
+    ));
+    let configs = machine_configs(
+        Some("/dev/ttyUSB0,/dev/ttyUSB1"),
+        Some("shelly:0@http://10.0.0.5,shelly:1@http://10.0.0.5"),
+        Some("/dev/gpiochip0:26"),
+    )
+    .unwrap();
+    assert!(configs
+        .iter()
+        .all(|(_, relay)| matches!(relay, RelayConfig::SmartPlug(_))));
+    assert!(machine_configs(Some("/dev/ttyUSB0,/dev/ttyUSB1"), None, None).is_err());
+    assert!(machine_configs(None, None, Some("/dev/gpiochip0:x")).is_err());
 }
@@ -1,18 +1,87 @@
+use dringos::relay::{self, Relay, RelayState};
 use gpio_cdev::{LineHandle, LineRequestFlags};
+use std::str::FromStr;
 
+/// Which GPIO line drives a relay and how.
+///
+/// Parsed from `CHIP:LINE[:active-low][:LABEL]`, like `/dev/gpiochip0:26` or
+/// `/dev/gpiochip1:17:active-low:dryer-2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwitchConfig {
+    pub chip: String,
+    pub line: u32,
+    /// The relay board closes when the line is driven low
+    pub active_low: bool,
+    /// Shows up as the line consumer in `gpioinfo`, should be unique per switch
+    pub consumer: String,
+}
+
+impl Default for SwitchConfig {
+    fn default() -> Self {
+        Self {
+            chip: "/dev/gpiochip0".to_string(),
+            line: 26,
+            active_low: false,
+            consumer: "dryer-switch".to_string(),
+        }
+    }
+}
+
+impl FromStr for SwitchConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let chip = match parts.next() {
+            Some(chip) if !chip.is_empty() => chip.to_string(),
+            _ => return Err(format!("Missing GPIO chip in `{}`", s)),
+        };
+        let line = parts
+            .next()
+            .ok_or_else(|| format!("Missing GPIO line in `{}`", s))?;
+        let line = line
+            .parse()
+            .map_err(|e| format!("Invalid GPIO line `{}` in `{}`: {}", line, s, e))?;
+        let mut config = Self {
+            chip,
+            line,
+            ..Self::default()
+        };
+        let mut rest = parts.peekable();
+        if rest.peek() == Some(&"active-low") {
+            config.active_low = true;
+            rest.next();
+        }
+        if let Some(consumer) = rest.next() {
+            config.consumer = consumer.to_string();
+        }
+        if rest.next().is_some() {
+            return Err(format!("Unexpected trailing fields in `{}`", s));
+        }
+        Ok(config)
+    }
+}
+
+/// Relay on a GPIO line, each switch holds its own line so one process can drive several
 #[derive(Debug)]
 pub struct EnergySwitch {
     switch_gpio: LineHandle,
 }
 
 impl EnergySwitch {
-    pub fn new() -> Self {
-        let mut chip =
-            gpio_cdev::Chip::new("/dev/gpiochip0").expect("Error initializing GPIO Chip!");
+    /// The line is requested with the relay open
+    pub fn new(config: &SwitchConfig) -> Self {
+        let mut chip = gpio_cdev::Chip::new(&config.chip)
+            .unwrap_or_else(|e| panic!("Error initializing GPIO Chip {}: {}", config.chip, e));
+        let mut flags = LineRequestFlags::OUTPUT;
+        if config.active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+        // values are logical from here on, the kernel inverts them for active low lines
         let switch_gpio = chip
-            .get_line(26)
+            .get_line(config.line)
             .expect("Error initializing GPIO switch line!")
-            .request(LineRequestFlags::OUTPUT, 0, "dryer-switch")
+            .request(flags, 0, &config.consumer)
             .expect("Error initializing GPIO switch line as output!");
         switch_gpio
             .set_value(0)
@@ -20,14 +89,52 @@ impl EnergySwitch {
 
         Self { switch_gpio }
     }
-    pub fn turn_on(&mut self) {
-        self.switch_gpio
-            .set_value(1)
-            .expect("Error setting dryer on!")
+}
+
+impl Relay for EnergySwitch {
+    fn turn_on(&mut self) -> Result<(), relay::Error> {
+        self.switch_gpio.set_value(1)?;
+        Ok(())
     }
-    pub fn turn_off(&mut self) {
-        self.switch_gpio
-            .set_value(0)
-            .expect("Error setting dryer off!")
+    fn turn_off(&mut self) -> Result<(), relay::Error> {
+        self.switch_gpio.set_value(0)?;
+        Ok(())
     }
+    fn state(&mut self) -> Result<RelayState, relay::Error> {
+        match self.switch_gpio.get_value()? {
+            0 => Ok(RelayState::Off),
+            _ => Ok(RelayState::On),
+        }
+    }
+}
+
+#[test]
+fn switch_configs_parse() {
+    assert_eq!(
+        "/dev/gpiochip0:26".parse::<SwitchConfig>().unwrap(),
+        SwitchConfig::default()
+    );
+    assert_eq!(
+        "/dev/gpiochip1:17:active-low:dryer-2"
+            .parse::<SwitchConfig>()
+            .unwrap(),
+        SwitchConfig {
+            chip: "/dev/gpiochip1".to_string(),
+            line: 17,
+            active_low: true,
+            consumer: "dryer-2".to_string(),
+        }
+    );
+    assert_eq!(
+        "/dev/gpiochip0:5:washer"
+            .parse::<SwitchConfig>()
+            .unwrap()
+            .consumer,
+        "washer"
+    );
+    assert!("/dev/gpiochip0".parse::<SwitchConfig>().is_err());
+    assert!("/dev/gpiochip0:x".parse::<SwitchConfig>().is_err());
+    assert!("/dev/gpiochip0:1:active-low:a:b"
+        .parse::<SwitchConfig>()
+        .is_err());
 }
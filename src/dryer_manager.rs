@@ -1,92 +1,479 @@
-use crate::dryer_machine::OffState;
-use crate::{MsgType, OutgoingMessage};
+use crate::dryer_machine::{HardwareFault, OffState};
+use crate::money::{self, EnergyPrice, Money};
+use crate::{Buttons, MsgType, OutgoingMessage};
+use dringos::meter::energy_delta_wh;
+use dringos::relay;
+use std::collections::VecDeque;
 
-const COST_REAIS_KWH: f64 = 1.1;
+/// Until an admin sets another one
+pub const DEFAULT_PRICE: EnergyPrice = EnergyPrice::from_centavos_per_kwh(110);
 const TURN_OFF_SECONDS_ZERO_POWER_THRESHOLD: u64 = 20;
-const MIN_AMOUNT_REAIS_TURN_ON: f64 = 1.;
+const MIN_BALANCE_TURN_ON: Money = Money::from_centavos(100);
+/// Ticks in a row without a meter reading before the dryer is turned off, since nothing it
+/// consumes can be billed meanwhile
+const MAX_CONSECUTIVE_METER_FAILURES: u32 = 10;
 const J_TO_WH_CONVERSION_FACTOR: f64 = 1. / 3600.;
+/// How far the power x time estimate may drift from the meter counter before we complain
+const CROSS_CHECK_TOLERANCE_WH: f64 = 20.;
+const CROSS_CHECK_TOLERANCE_RATIO: f64 = 0.1;
+/// Longest the process may have been down for a cycle to be resumed, past that whoever was
+/// drying has probably given up waiting
+const MAX_RESUME_DOWNTIME_SECONDS: i64 = 10 * 60;
+/// Window the average power shown to the user is taken over
+const STATUS_POWER_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
 pub enum State {
     On(OnState),
     OffState(super::dryer_machine::OffState),
 }
 
-impl State {
-    pub fn new() -> Self {
-        Self::OffState(OffState::new())
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct User {
     pub telegram_id: u64,
     pub chat_id: i64,
     pub name: String,
-    pub balance_reais: f64,
+    pub balance: Money,
 }
 
 pub struct OnState {
     drier_on_state: super::dryer_machine::OnState,
     cycle_stats: CycleStats,
     start_time_zero_power: Option<std::time::Instant>,
+    consecutive_meter_failures: u32,
 }
 
+/// Cycles are billed from the meter energy counter, the power x time integration is only kept
+/// to cross-check it, since it misses whatever happens between ticks or while the loop stalls.
+///
+/// Energy is charged a whole centavo at a time, what it costs beyond that is carried to the
+/// next tick. Whatever is still carried when the cycle ends, less than a centavo, is not charged.
 #[derive(Debug, Clone)]
 pub struct CycleStats {
+    /// Number of the machine it runs on
+    pub machine: u8,
     pub start_time: std::time::Instant,
+    /// Same as `start_time`, but survives restarts
+    pub started_at: chrono::DateTime<chrono::Utc>,
     pub time_last_tick_update: std::time::Instant,
-    pub partial_undiscounted_accumulated_joules: f64,
-    total_consumed_and_discounted_wh: f64,
-    pub total_consumed_and_discounted_reais: f64,
+    /// Meter energy counter when the cycle started
+    pub start_meter_energy_wh: u32,
+    pub last_meter_energy_wh: u32,
+    /// Energy measured by the meter since the cycle started
+    pub consumed_wh: u32,
+    pub charged: Money,
+    /// Cost of consumed energy not charged yet, in thousandths of a centavo
+    pub carried_millicentavos: i64,
+    /// power x time integration, for cross-checking only
+    estimated_consumed_joules: f64,
     pub user: User,
 }
 
 impl CycleStats {
+    /// Picks up a cycle saved before a restart, everything the meter counted since it was saved
+    /// is billed on the next tick
+    pub(crate) fn restore(saved: crate::database::ActiveCycle) -> Self {
+        let running_for = (chrono::Utc::now() - saved.started_at)
+            .to_std()
+            .unwrap_or_default();
+        let consumed_wh = u32::try_from(saved.consumed_wh).unwrap_or(0);
+        Self {
+            machine: u8::try_from(saved.machine)
+                .expect("Error converting machine number from i64 to u8"),
+            start_time: std::time::Instant::now()
+                .checked_sub(running_for)
+                .unwrap_or_else(std::time::Instant::now),
+            started_at: saved.started_at,
+            time_last_tick_update: std::time::Instant::now(),
+            start_meter_energy_wh: u32::try_from(saved.start_meter_energy_wh).unwrap_or(0),
+            last_meter_energy_wh: u32::try_from(saved.last_meter_energy_wh).unwrap_or(0),
+            consumed_wh,
+            charged: saved.charged,
+            carried_millicentavos: saved.carried_millicentavos,
+            // the estimate before the restart is lost, start it where the meter was
+            estimated_consumed_joules: f64::from(consumed_wh) / J_TO_WH_CONVERSION_FACTOR,
+            user: User {
+                telegram_id: u64::try_from(saved.telegram_id)
+                    .expect("Error converting telegram id from i64 to u64"),
+                chat_id: saved.chat_id,
+                name: saved.name,
+                balance: saved.balance,
+            },
+        }
+    }
+
     pub fn total_consumed_kwh(&self) -> f64 {
-        self.total_consumed_and_discounted_wh / 1000.
+        f64::from(self.consumed_wh) / 1000.
+    }
+
+    pub fn estimated_consumed_wh(&self) -> f64 {
+        self.estimated_consumed_joules * J_TO_WH_CONVERSION_FACTOR
+    }
+
+    fn cross_check_estimate(&self) {
+        let measured = f64::from(self.consumed_wh);
+        let estimated = self.estimated_consumed_wh();
+        let tolerance = CROSS_CHECK_TOLERANCE_WH.max(measured * CROSS_CHECK_TOLERANCE_RATIO);
+        if (measured - estimated).abs() > tolerance {
+            log::warn!(
+                "Cycle of {} measured {:.1}Wh on the meter ({}Wh -> {}Wh) but power x time estimated {:.1}Wh",
+                self.user.name,
+                measured,
+                self.start_meter_energy_wh,
+                self.last_meter_energy_wh,
+                estimated
+            );
+        } else {
+            log::info!(
+                "Cycle of {} measured {:.1}Wh on the meter ({}Wh -> {}Wh), power x time estimated {:.1}Wh",
+                self.user.name,
+                measured,
+                self.start_meter_energy_wh,
+                self.last_meter_energy_wh,
+                estimated
+            );
+        }
     }
 }
 
 pub struct DryerManager {
+    /// Machines are numbered from 1, in the order they are configured
+    number: u8,
     state: Option<State>,
+    /// Changes to the active cycle not saved to the database yet, oldest first
+    cycle_updates: VecDeque<CycleUpdate>,
+    /// Kind of the last fault reported, so it isn't reported again on every tick
+    reported_fault: Option<&'static str>,
+    /// The relay refused to open when the last cycle ended, retried on every tick
+    relay_fault: Option<HardwareFault>,
+    price: EnergyPrice,
 }
 
 pub enum TickOutcome {
     TurnOffAndRemoveUserOutOfMoney(CycleStats),
     TurnedOffDueToIdleTooLong(CycleStats),
+    TurnedOffDueToMeterFailure {
+        cycle_stats: CycleStats,
+        error: String,
+    },
+    MeterUnavailable,
+    /// `charge` is still to be recorded, along with the cycle it was billed for
     DiscountConsumed {
-        delta_energy_kwh: f64,
-        delta_consumed_reais: f64,
-        user: User,
+        charge: Money,
+        cycle_stats: CycleStats,
     },
     NotEnoughConsumptionToDiscountYet,
     Off,
 }
 
+/// Why a cycle ended, kept in its history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    OutOfMoney,
+    Idle,
+    MeterFailure,
+    /// Closed instead of resumed after a restart
+    Restart,
+    /// Closed after a restart without reading the meter, whatever was consumed while the system
+    /// was down wasn't charged
+    RestartEnergyUnknown,
+    /// Turned off because of an internal error, like the database being unreachable
+    Emergency,
+    /// Turned off by an admin
+    Forced,
+}
+
+impl EndReason {
+    /// Stable name, for storing
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EndReason::OutOfMoney => "out_of_money",
+            EndReason::Idle => "idle",
+            EndReason::MeterFailure => "meter_failure",
+            EndReason::Restart => "restart",
+            EndReason::RestartEnergyUnknown => "restart_energy_unknown",
+            EndReason::Emergency => "emergency",
+            EndReason::Forced => "forced",
+        }
+    }
+
+    /// For the user
+    pub fn description(&self) -> &'static str {
+        match self {
+            EndReason::OutOfMoney => "falta de saldo",
+            EndReason::Idle => "secadora parada",
+            EndReason::MeterFailure => "falha no medidor",
+            EndReason::Restart => "sistema reiniciado",
+            EndReason::RestartEnergyUnknown => "sistema reiniciado, consumo final desconhecido",
+            EndReason::Emergency => "erro interno",
+            EndReason::Forced => "desligada por um administrador",
+        }
+    }
+}
+
+impl std::str::FromStr for EndReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            EndReason::OutOfMoney,
+            EndReason::Idle,
+            EndReason::MeterFailure,
+            EndReason::Restart,
+            EndReason::RestartEnergyUnknown,
+            EndReason::Emergency,
+            EndReason::Forced,
+        ]
+        .into_iter()
+        .find(|reason| reason.as_str() == s)
+        .ok_or_else(|| format!("Unknown cycle end reason `{}`", s))
+    }
+}
+
+/// What has to be saved about the active cycle
+#[derive(Debug, Clone)]
+pub enum CycleUpdate {
+    Active(CycleStats),
+    Ended(CycleStats, EndReason),
+}
+
+/// What became of a cycle interrupted by a restart
+#[derive(Debug, Clone)]
+pub enum RecoveredCycle {
+    Resumed(CycleStats),
+    /// `charge` is what was consumed while the system was down, already counted in the cycle
+    /// but still to be recorded
+    Closed {
+        cycle_stats: CycleStats,
+        charge: Money,
+        reason: EndReason,
+    },
+}
+
 impl DryerManager {
-    pub fn new() -> Self {
-        Self {
-            state: Some(State::new()),
+    /// Drives the given machine, like one built on the emulator by [`OffState::with_meter`].
+    /// If its relay couldn't be opened that is a fault, retried until it opens.
+    pub fn with_machine(number: u8, machine: Result<OffState, (OffState, relay::Error)>) -> Self {
+        let mut manager = Self {
+            number,
+            state: None,
+            cycle_updates: VecDeque::new(),
+            reported_fault: None,
+            relay_fault: None,
+            price: DEFAULT_PRICE,
+        };
+        let off = match machine {
+            Ok(off) => off,
+            Err((off, e)) => {
+                manager.relay_failed(e);
+                off
+            }
+        };
+        manager.state = Some(State::OffState(off));
+        manager
+    }
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+    fn relay_failed(&mut self, e: relay::Error) {
+        log::error!("Error setting dryer {} off: {}", self.number, e);
+        self.relay_fault = Some(HardwareFault::RelayNotOff {
+            error: e.to_string(),
+        });
+    }
+    /// Ends the running cycle, if any, when it can't go on safely, returning it
+    pub async fn emergency_turn_off(&mut self) -> Option<CycleStats> {
+        self.turn_off(EndReason::Emergency).await
+    }
+    /// Ends the running cycle, if any, returning it
+    pub async fn force_turn_off(&mut self) -> Option<CycleStats> {
+        self.turn_off(EndReason::Forced).await
+    }
+    /// Opens the relay to end a cycle. Nothing can be billed without a cycle, so the dryer is
+    /// off from then on even if the relay refuses, but that is kept to be reported as a fault
+    /// and retried.
+    async fn open_relay(&mut self, on: super::dryer_machine::OnState) -> OffState {
+        match on.turn_off().await {
+            Ok(off) => off,
+            Err((off, e)) => {
+                self.relay_failed(e);
+                off
+            }
         }
     }
-    pub fn emergency_turn_off(&mut self) {
+    /// The relay refused to open on startup or when the last cycle ended and still does, whoever is told the
+    /// cycle ended should know the dryer may still be powered
+    pub fn relay_stuck(&self) -> bool {
+        self.relay_fault.is_some()
+    }
+    async fn turn_off(&mut self, reason: EndReason) -> Option<CycleStats> {
         let state = self.state.take().expect("State should have been initiated");
-        let new_state = match state {
-            State::On(on) => State::OffState(on.drier_on_state.turn_off()),
-            State::OffState(off) => State::OffState(off),
+        let (new_state, cycle_stats) = match state {
+            State::On(on) => {
+                on.cycle_stats.cross_check_estimate();
+                self.queue_cycle_update(CycleUpdate::Ended(on.cycle_stats.clone(), reason));
+                (
+                    State::OffState(self.open_relay(on.drier_on_state).await),
+                    Some(on.cycle_stats),
+                )
+            }
+            State::OffState(off) => (State::OffState(off), None),
         };
         self.state = Some(new_state);
+        cycle_stats
+    }
+    /// The running cycle, if any
+    pub fn active_cycle(&self) -> Option<&CycleStats> {
+        match self
+            .state
+            .as_ref()
+            .expect("State should have been initiated")
+        {
+            State::On(on) => Some(&on.cycle_stats),
+            State::OffState(_) => None,
+        }
+    }
+    pub fn price(&self) -> EnergyPrice {
+        self.price
+    }
+    /// Applies from the next tick on, including to the running cycle
+    pub fn set_price(&mut self, price: EnergyPrice) {
+        self.price = price;
+    }
+    /// A newer snapshot of the running cycle makes a pending one pointless
+    fn queue_cycle_update(&mut self, update: CycleUpdate) {
+        if let (CycleUpdate::Active(_), Some(CycleUpdate::Active(_))) =
+            (&update, self.cycle_updates.back())
+        {
+            self.cycle_updates.pop_back();
+        }
+        self.cycle_updates.push_back(update);
+    }
+    /// Oldest change to the active cycle not saved yet, it stays pending until
+    /// [`DryerManager::cycle_update_saved`] so a failure to save it can be retried
+    pub fn pending_cycle_update(&self) -> Option<&CycleUpdate> {
+        self.cycle_updates.front()
+    }
+    pub fn cycle_update_saved(&mut self) {
+        self.cycle_updates.pop_front();
+    }
+    /// A charge that couldn't be recorded isn't owed, it comes off what the cycle charged, be it
+    /// still running or ended and waiting to be saved
+    pub fn charge_failed(&mut self, charge: Money) {
+        if let Some(State::On(on)) = self.state.as_mut() {
+            on.cycle_stats.charged -= charge;
+        } else if let Some(CycleUpdate::Ended(cycle_stats, _)) = self.cycle_updates.back_mut() {
+            cycle_stats.charged -= charge;
+        }
+    }
+    /// Turns the dryer back on for a cycle saved before a restart, unless the process was down
+    /// for too long or the user is out of money, in which case the cycle is closed
+    pub async fn recover_cycle(&mut self, saved: crate::database::ActiveCycle) -> RecoveredCycle {
+        let downtime_s = (chrono::Utc::now() - saved.updated_at).num_seconds();
+        let cycle_stats = CycleStats::restore(saved);
+        let off = match self.state.take().expect("State should have been initiated") {
+            State::OffState(off) => off,
+            State::On(on) => self.open_relay(on.drier_on_state).await,
+        };
+        if downtime_s > MAX_RESUME_DOWNTIME_SECONDS || cycle_stats.user.balance <= Money::ZERO {
+            log::info!(
+                "Closing cycle of {} interrupted {}s ago",
+                cycle_stats.user.name,
+                downtime_s
+            );
+            return self.close_recovered_cycle(off, cycle_stats).await;
+        }
+        match off.turn_on().await {
+            Ok(on) => {
+                self.relay_fault = None;
+                log::info!(
+                    "Resuming cycle of {} interrupted {}s ago",
+                    cycle_stats.user.name,
+                    downtime_s
+                );
+                self.state = Some(State::On(OnState {
+                    drier_on_state: on,
+                    cycle_stats: cycle_stats.clone(),
+                    start_time_zero_power: None,
+                    consecutive_meter_failures: 0,
+                }));
+                self.queue_cycle_update(CycleUpdate::Active(cycle_stats.clone()));
+                RecoveredCycle::Resumed(cycle_stats)
+            }
+            Err((off, e)) => {
+                log::error!("Error turning the dryer on to resume a cycle: {}", e);
+                self.close_recovered_cycle(off, cycle_stats).await
+            }
+        }
+    }
+    /// Bills what the meter counted since the cycle was last saved, as a tick would have. If
+    /// the meter can't be read that is left uncharged and the cycle ends saying so.
+    async fn close_recovered_cycle(
+        &mut self,
+        mut off: OffState,
+        mut cycle_stats: CycleStats,
+    ) -> RecoveredCycle {
+        let (charge, reason) = match off.read_fresh_data().await {
+            Ok(data) => {
+                let delta_wh = energy_delta_wh(cycle_stats.last_meter_energy_wh, data.energy_wh);
+                cycle_stats.last_meter_energy_wh = data.energy_wh;
+                cycle_stats.consumed_wh += delta_wh;
+                let (charge, carried_millicentavos) = money::split_millicentavos(
+                    cycle_stats.carried_millicentavos + self.price.cost_millicentavos(delta_wh),
+                );
+                cycle_stats.carried_millicentavos = carried_millicentavos;
+                cycle_stats.charged += charge;
+                (charge, EndReason::Restart)
+            }
+            Err(e) => {
+                log::error!("Error reading pzem data to close a recovered cycle: {}", e);
+                (Money::ZERO, EndReason::RestartEnergyUnknown)
+            }
+        };
+        self.state = Some(State::OffState(off));
+        self.queue_cycle_update(CycleUpdate::Ended(cycle_stats.clone(), reason));
+        RecoveredCycle::Closed {
+            cycle_stats,
+            charge,
+            reason,
+        }
     }
-    fn process_on_state_tick(mut on: OnState) -> (State, TickOutcome) {
+    async fn process_on_state_tick(&mut self, mut on: OnState) -> (State, TickOutcome) {
         // check if user is out of money
-        if on.cycle_stats.user.balance_reais <= 0.001 {
-            let off = on.drier_on_state.turn_off();
+        if on.cycle_stats.user.balance <= Money::ZERO {
+            let off = self.open_relay(on.drier_on_state).await;
+            on.cycle_stats.cross_check_estimate();
             return (
                 State::OffState(off),
                 TickOutcome::TurnOffAndRemoveUserOutOfMoney(on.cycle_stats),
             );
         }
         // if we are at "zero power" and we weren't before, mark it as being now
-        let current_power = on.drier_on_state.get_current_power();
+        let data = match on.drier_on_state.read_data().await {
+            Ok(data) => {
+                on.consecutive_meter_failures = 0;
+                data
+            }
+            Err(e) => {
+                on.consecutive_meter_failures += 1;
+                log::error!(
+                    "Error reading pzem data ({} in a row): {}",
+                    on.consecutive_meter_failures,
+                    e
+                );
+                if on.consecutive_meter_failures < MAX_CONSECUTIVE_METER_FAILURES {
+                    return (State::On(on), TickOutcome::MeterUnavailable);
+                }
+                let off = self.open_relay(on.drier_on_state).await;
+                on.cycle_stats.cross_check_estimate();
+                return (
+                    State::OffState(off),
+                    TickOutcome::TurnedOffDueToMeterFailure {
+                        cycle_stats: on.cycle_stats,
+                        error: e.to_string(),
+                    },
+                );
+            }
+        };
+        let current_power = data.power_w;
         if current_power <= 1. && on.start_time_zero_power.is_none() {
             on.start_time_zero_power = Some(std::time::Instant::now())
         }
@@ -100,33 +487,33 @@ impl DryerManager {
                 // if we've been at "zero power" more time than the threshold, consider it
                 // as turned off
                 // turn off and remove user
-                let off = on.drier_on_state.turn_off();
+                let off = self.open_relay(on.drier_on_state).await;
+                on.cycle_stats.cross_check_estimate();
                 return (
                     State::OffState(off),
                     TickOutcome::TurnedOffDueToIdleTooLong(on.cycle_stats),
                 );
             }
         }
-        let current_power_w = on.drier_on_state.get_current_power() as f64;
         let time_elapsed_s = on.cycle_stats.time_last_tick_update.elapsed().as_secs_f64();
-        let joules = current_power_w * time_elapsed_s;
+        on.cycle_stats.estimated_consumed_joules += current_power as f64 * time_elapsed_s;
         on.cycle_stats.time_last_tick_update = std::time::Instant::now();
-        on.cycle_stats.partial_undiscounted_accumulated_joules += joules;
-        let partial_acc_joules = on.cycle_stats.partial_undiscounted_accumulated_joules;
-        let partial_consumed_wh = partial_acc_joules as f64 * J_TO_WH_CONVERSION_FACTOR;
-        let partial_consumed_kwh = partial_consumed_wh / 1000.;
-        let partial_consumed_reais = partial_consumed_kwh * COST_REAIS_KWH;
-        if partial_consumed_reais >= 0.01 {
-            on.cycle_stats.total_consumed_and_discounted_wh += partial_consumed_wh;
-            on.cycle_stats.total_consumed_and_discounted_reais += partial_consumed_reais;
-            on.cycle_stats.partial_undiscounted_accumulated_joules = 0.;
-            let user = on.cycle_stats.user.clone();
+
+        let delta_wh = energy_delta_wh(on.cycle_stats.last_meter_energy_wh, data.energy_wh);
+        on.cycle_stats.last_meter_energy_wh = data.energy_wh;
+        on.cycle_stats.consumed_wh += delta_wh;
+        let (charge, carried_millicentavos) = money::split_millicentavos(
+            on.cycle_stats.carried_millicentavos + self.price.cost_millicentavos(delta_wh),
+        );
+        on.cycle_stats.carried_millicentavos = carried_millicentavos;
+        if charge > Money::ZERO {
+            on.cycle_stats.charged += charge;
+            let cycle_stats = on.cycle_stats.clone();
             (
                 State::On(on),
                 TickOutcome::DiscountConsumed {
-                    delta_energy_kwh: partial_consumed_kwh,
-                    delta_consumed_reais: partial_consumed_reais,
-                    user,
+                    charge,
+                    cycle_stats,
                 },
             )
         } else {
@@ -136,50 +523,126 @@ impl DryerManager {
             )
         }
     }
-    pub fn set_user_balance_reais(&mut self, balance_reais: f64) {
+    pub fn set_user_balance(&mut self, balance: Money) {
         let current_state = self
             .state
             .as_mut()
             .expect("State should have been initiated");
         match current_state {
             State::On(on) => {
-                on.cycle_stats.user.balance_reais = balance_reais;
+                on.cycle_stats.user.balance = balance;
             }
             State::OffState(_) => {
                 panic!("Logic bug, can't set user when off!")
             }
         }
     }
-    pub fn tick(&mut self) -> TickOutcome {
+    /// Keeps the balance of whoever is drying in sync when it changes outside the cycle, like
+    /// on a top up
+    pub fn balance_changed(&mut self, telegram_id: u64, balance: Money) {
+        if let Some(State::On(on)) = self.state.as_mut() {
+            if on.cycle_stats.user.telegram_id == telegram_id {
+                on.cycle_stats.user.balance = balance;
+            }
+        }
+    }
+    /// Faults found by cross-checking the relay with the meter, each one is only returned once,
+    /// until it clears or turns into another
+    pub fn check_hardware(&mut self) -> Option<HardwareFault> {
+        let fault = match self
+            .state
+            .as_ref()
+            .expect("State should have been initiated")
+        {
+            State::On(on) => on.drier_on_state.check_hardware(),
+            State::OffState(off) => self.relay_fault.clone().or_else(|| off.check_hardware()),
+        };
+        let kind = fault.as_ref().map(HardwareFault::kind);
+        if kind == self.reported_fault {
+            return None;
+        }
+        if kind.is_none() {
+            log::info!("Hardware fault {:?} cleared", self.reported_fault);
+        }
+        self.reported_fault = kind;
+        fault
+    }
+    pub async fn tick(&mut self) -> TickOutcome {
         let current_state = self.state.take().expect("State should have been initiated");
         let (state, tick_outcome) = match current_state {
-            State::On(on_state) => Self::process_on_state_tick(on_state),
-            State::OffState(off_state) => (State::OffState(off_state), TickOutcome::Off),
+            State::On(on_state) => self.process_on_state_tick(on_state).await,
+            State::OffState(mut off_state) => {
+                if self.relay_fault.is_some() && off_state.turn_off().await.is_ok() {
+                    log::info!("Dryer relay finally turned off");
+                    self.relay_fault = None;
+                }
+                (State::OffState(off_state), TickOutcome::Off)
+            }
         };
+        match (&tick_outcome, &state) {
+            (TickOutcome::DiscountConsumed { .. }, _) => {
+                // saved along with the charge, a snapshot from before it would be stale
+                if let Some(CycleUpdate::Active(_)) = self.cycle_updates.back() {
+                    self.cycle_updates.pop_back();
+                }
+            }
+            (TickOutcome::TurnOffAndRemoveUserOutOfMoney(cycle_stats), _) => {
+                self.queue_cycle_update(CycleUpdate::Ended(
+                    cycle_stats.clone(),
+                    EndReason::OutOfMoney,
+                ));
+            }
+            (TickOutcome::TurnedOffDueToIdleTooLong(cycle_stats), _) => {
+                self.queue_cycle_update(CycleUpdate::Ended(cycle_stats.clone(), EndReason::Idle));
+            }
+            (TickOutcome::TurnedOffDueToMeterFailure { cycle_stats, .. }, _) => {
+                self.queue_cycle_update(CycleUpdate::Ended(
+                    cycle_stats.clone(),
+                    EndReason::MeterFailure,
+                ));
+            }
+            _ => {}
+        }
         self.state = Some(state);
         tick_outcome
     }
-    fn get_status_message(&mut self, telegram_id: u64, user_balance: f64) -> String {
+    pub async fn get_status_message(&mut self, telegram_id: u64, user_balance: Money) -> String {
         let state = self
             .state
             .as_mut()
             .expect("State should have been initiated");
-        return match state {
+        match state {
             State::On(on) => {
-                let power_now = on.drier_on_state.get_current_power();
                 if on.cycle_stats.user.telegram_id == telegram_id {
+                    let power = match on.drier_on_state.get_current_power().await {
+                        Ok(power_now) => {
+                            let average_power = on
+                                .drier_on_state
+                                .power_stats(STATUS_POWER_WINDOW)
+                                .map(|stats| stats.average_w)
+                                .unwrap_or(power_now);
+                            format!(
+                                "{:.2}W (média do último minuto: {:.2}W)",
+                                power_now, average_power
+                            )
+                        }
+                        Err(e) => {
+                            log::error!("Error reading pzem data: {}", e);
+                            "indisponível".to_string()
+                        }
+                    };
                     let cycle_stats = &on.cycle_stats;
                     format!(
-                        "Secando há {cycle_time}. Custo até o momento: R${cost_reais:.2} referentes a {kwh:.2} kwh consumidos. Saldo remanescente de {balance:.2}. Potencia atual: {power:.2}W",
+                        "Secando há {cycle_time}. Custo até o momento: R${cost} referentes a {kwh:.2} kwh consumidos. Saldo remanescente de {balance}. Potencia atual: {power}",
                         cycle_time= crate::seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
-                        cost_reais=cycle_stats.total_consumed_and_discounted_reais,
+                        cost=cycle_stats.charged,
                         kwh=cycle_stats.total_consumed_kwh(),
-                        balance=cycle_stats.user.balance_reais,
-                        power=power_now
+                        balance=cycle_stats.user.balance,
+                        power=power
                     )
                 } else {
                     format!(
-                        "{} está usando a secadora há: {}. O seu saldo remanescente é de: {balance:.2}.",
+                        "{} está usando a secadora há: {}. O seu saldo remanescente é de: {balance}.",
                         on.cycle_stats.user.name,
                         crate::seconds_to_hour_format(
                             on.cycle_stats.start_time.elapsed().as_secs()
@@ -189,46 +652,79 @@ impl DryerManager {
                 }
             }
             State::OffState(_) => format!(
-                "A secadora está livre! Saldo remanescente: {balance:.2}.",
+                "A secadora está livre! Saldo remanescente: {balance}.",
                 balance = user_balance
             ),
-        };
+        }
     }
 
-    fn turn_on_for_user(
+    async fn turn_on_for_user(
+        &mut self,
         off_state: OffState,
         user: super::telegram::UserMessage,
         db_user: super::database::User,
-    ) -> OnState {
-        let on = off_state.turn_on();
-        OnState {
+    ) -> Result<OnState, (OffState, String)> {
+        let mut on = match off_state.turn_on().await {
+            Ok(on) => {
+                self.relay_fault = None;
+                on
+            }
+            Err((off_state, e)) => {
+                log::error!("Error turning the dryer on: {}", e);
+                return Err((
+                    off_state,
+                    "Não foi possível ligar a secadora. Tente novamente em alguns instantes."
+                        .to_string(),
+                ));
+            }
+        };
+        // a sample from before the relay closed could miss the first Wh of the cycle
+        let start_meter_energy_wh = match on.read_fresh_data().await {
+            Ok(data) => data.energy_wh,
+            // can't bill a cycle without knowing where the meter counter started
+            Err(e) => {
+                log::error!("Error reading pzem data when turning on: {}", e);
+                return Err((
+                    self.open_relay(on).await,
+                    "Não foi possível ler o medidor de energia, a secadora continua desligada. Tente novamente em alguns instantes.".to_string(),
+                ));
+            }
+        };
+        Ok(OnState {
             drier_on_state: on,
             start_time_zero_power: None,
+            consecutive_meter_failures: 0,
             cycle_stats: CycleStats {
+                machine: self.number,
                 start_time: std::time::Instant::now(),
+                started_at: chrono::Utc::now(),
                 time_last_tick_update: std::time::Instant::now(),
-                partial_undiscounted_accumulated_joules: 0.0,
-                total_consumed_and_discounted_wh: 0.0,
-                total_consumed_and_discounted_reais: 0.0,
+                start_meter_energy_wh,
+                last_meter_energy_wh: start_meter_energy_wh,
+                consumed_wh: 0,
+                charged: Money::ZERO,
+                carried_millicentavos: 0,
+                estimated_consumed_joules: 0.0,
                 user: User {
                     telegram_id: user.user_id,
                     chat_id: user.chat_id,
                     name: db_user.name,
-                    balance_reais: db_user.balance_reais,
+                    balance: db_user.balance,
                 },
             },
-        }
+        })
     }
 
-    pub fn handle_turn_state_change(
+    pub async fn handle_turn_state_change(
+        &mut self,
         current: State,
         user: super::telegram::UserMessage,
         db_user: super::database::User,
     ) -> (State, String) {
-        return match current {
+        match current {
             State::On(on) => {
                 if on.cycle_stats.user.telegram_id == user.user_id {
-                    (State::On(on), format!("Você já está usando a secadora."))
+                    (State::On(on), "Você já está usando a secadora.".to_string())
                 } else {
                     let msg = format!(
                         "{} está usando a secadora há: {}",
@@ -241,20 +737,29 @@ impl DryerManager {
                 }
             }
             State::OffState(off_state) => {
-                if db_user.balance_reais <= MIN_AMOUNT_REAIS_TURN_ON {
-                    return (State::OffState(off_state), format!("Você precisa de ao menos {} reais de saldo para ligar a secadora, você possui: R${:.2}.", MIN_AMOUNT_REAIS_TURN_ON, db_user.balance_reais));
+                if db_user.balance <= MIN_BALANCE_TURN_ON {
+                    (State::OffState(off_state), format!("Você precisa de mais de R${} de saldo para ligar a secadora, você possui: R${}.", MIN_BALANCE_TURN_ON, db_user.balance))
                 } else {
-                    let state = Self::turn_on_for_user(off_state, user, db_user.clone());
-                    (
-                        State::On(state),
-                        format!("Ligada, você tem R${:.2}", db_user.balance_reais),
-                    )
+                    match self
+                        .turn_on_for_user(off_state, user, db_user.clone())
+                        .await
+                    {
+                        Ok(state) => (
+                            State::On(state),
+                            format!(
+                                "Ligada, você tem R${}, o kWh custa R${}",
+                                db_user.balance,
+                                self.price.per_kwh()
+                            ),
+                        ),
+                        Err((off_state, msg)) => (State::OffState(off_state), msg),
+                    }
                 }
             }
-        };
+        }
     }
 
-    fn handle_turn_on_message(
+    async fn handle_turn_on_message(
         &mut self,
         user: super::telegram::UserMessage,
         db_user: super::database::User,
@@ -263,35 +768,323 @@ impl DryerManager {
             .state
             .take()
             .expect("State should have been initialized by now!");
-        let (new_state, response) = Self::handle_turn_state_change(state, user, db_user);
+        let was_off = matches!(state, State::OffState(_));
+        let (new_state, response) = self.handle_turn_state_change(state, user, db_user).await;
+        if let (true, State::On(on)) = (was_off, &new_state) {
+            self.queue_cycle_update(CycleUpdate::Active(on.cycle_stats.clone()));
+        }
         self.state = Some(new_state);
         response
     }
 
-    pub fn handle_telegram_msg(
+    pub async fn handle_telegram_msg(
         &mut self,
         user_msg: super::telegram::UserMessage,
         db_user: super::database::User,
     ) -> OutgoingMessage {
         match user_msg.update {
-            MsgType::GenericMsg => OutgoingMessage {
+            // answered from the database before reaching the dryer
+            MsgType::GenericMsg
+            | MsgType::History { .. }
+            | MsgType::Statement { .. }
+            | MsgType::Register { .. }
+            | MsgType::ApproveRegistration { .. }
+            | MsgType::RejectRegistration { .. }
+            | MsgType::Admin(_)
+            | MsgType::InvalidCommand { .. }
+            | MsgType::Help
+            | MsgType::UnknownCommand { .. } => OutgoingMessage {
                 update_message_with_id: None,
                 chat_id: user_msg.chat_id,
-                text: self.get_status_message(user_msg.user_id, db_user.balance_reais),
-                send_buttons: true,
+                text: self
+                    .get_status_message(user_msg.user_id, db_user.balance)
+                    .await,
+                buttons: Buttons::Dryer,
             },
-            MsgType::TurnOn => OutgoingMessage {
+            MsgType::TurnOn { .. } => OutgoingMessage {
                 update_message_with_id: user_msg.message_id,
                 chat_id: user_msg.chat_id,
-                text: self.handle_turn_on_message(user_msg, db_user),
-                send_buttons: true,
+                text: self.handle_turn_on_message(user_msg, db_user).await,
+                buttons: Buttons::Dryer,
             },
             MsgType::Update => OutgoingMessage {
                 update_message_with_id: user_msg.message_id,
                 chat_id: user_msg.chat_id,
-                text: self.get_status_message(user_msg.user_id, db_user.balance_reais),
-                send_buttons: true,
+                text: self
+                    .get_status_message(user_msg.user_id, db_user.balance)
+                    .await,
+                buttons: Buttons::Dryer,
             },
         }
     }
 }
+
+/// Manager on an emulated meter and a mock relay, the meter counter starts at 1000Wh
+#[cfg(test)]
+fn emulated_dryer() -> (
+    DryerManager,
+    dringos::emulator::Emulator,
+    dringos::relay::MockRelay,
+) {
+    let emulator = dringos::emulator::Emulator::with_manual_clock();
+    emulator.set_energy_wh(1000);
+    let relay = dringos::relay::MockRelay::new();
+    let off = OffState::with_meter(dringos::pzemv3::Pzem::new(emulator.port()), relay.clone());
+    (DryerManager::with_machine(1, off), emulator, relay)
+}
+
+/// Turns the dryer on for user 42, as if they pressed the button
+#[cfg(test)]
+async fn start_cycle(dryer: &mut DryerManager, balance: Money) {
+    let user_message = super::telegram::UserMessage {
+        message_id: None,
+        message_text: None,
+        user_id: 42,
+        user_name: "fulano".to_string(),
+        chat_id: 420,
+        update: MsgType::TurnOn { machine: None },
+    };
+    let db_user = crate::database::User {
+        telegram_id: 42,
+        name: "Fulano".to_string(),
+        balance,
+        is_admin: false,
+        blocked: false,
+    };
+    dryer.handle_turn_on_message(user_message, db_user).await;
+    assert!(dryer.active_cycle().is_some());
+}
+
+/// Ticks like the main loop does, recording every charge, returns what was charged
+#[cfg(test)]
+async fn tick_and_charge(dryer: &mut DryerManager) -> (TickOutcome, Money) {
+    let outcome = dryer.tick().await;
+    let mut charged = Money::ZERO;
+    if let TickOutcome::DiscountConsumed {
+        charge,
+        cycle_stats,
+    } = &outcome
+    {
+        dryer.set_user_balance(cycle_stats.user.balance - *charge);
+        charged = *charge;
+    }
+    (outcome, charged)
+}
+
+#[tokio::test(start_paused = true)]
+async fn cycles_are_billed_from_the_meter_over_a_power_curve() {
+    use std::time::Duration;
+    let (mut dryer, emulator, relay) = emulated_dryer();
+    // heats at 2kW for half an hour, then only the drum turns
+    emulator.set_power_curve(|elapsed| {
+        if elapsed < Duration::from_secs(30 * 60) {
+            2000.
+        } else {
+            200.
+        }
+    });
+    start_cycle(&mut dryer, Money::from_centavos(1000)).await;
+    assert_eq!(relay.current_state(), dringos::relay::RelayState::On);
+    let mut charged = Money::ZERO;
+    for _ in 0..40 {
+        emulator.advance(Duration::from_secs(60));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        charged += tick_and_charge(&mut dryer).await.1;
+    }
+    // the last readings may have come from a sample taken before the meter moved
+    while dryer.active_cycle().unwrap().last_meter_energy_wh != emulator.energy_wh() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        charged += tick_and_charge(&mut dryer).await.1;
+    }
+    let cycle = dryer.active_cycle().unwrap();
+    let consumed_wh = emulator.energy_wh() - 1000;
+    // 1kWh heating and 10 minutes at 200W
+    assert!((1032..=1034).contains(&consumed_wh), "{}", consumed_wh);
+    assert_eq!(cycle.consumed_wh, consumed_wh);
+    assert_eq!(cycle.charged, charged);
+    // fractions of a centavo are carried between ticks, so nothing is lost to rounding
+    let (expected, _) = money::split_millicentavos(DEFAULT_PRICE.cost_millicentavos(consumed_wh));
+    assert_eq!(charged, expected);
+    assert_eq!(cycle.user.balance, Money::from_centavos(1000) - charged);
+    // the snapshot taken when it turned on is saved along with the charges
+    assert!(dryer.pending_cycle_update().is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn repeated_meter_failures_turn_the_dryer_off() {
+    use dringos::emulator::Fault;
+    use dringos::relay::RelayState;
+    let (mut dryer, emulator, relay) = emulated_dryer();
+    emulator.set_power_w(2000.);
+    start_cycle(&mut dryer, Money::from_centavos(1000)).await;
+    for _ in 0..10_000 {
+        emulator.inject(Fault::NoReply);
+    }
+    // samples are timestamped by the wall clock, the last good one must be too old to be used
+    std::thread::sleep(
+        super::dryer_machine::MAX_SAMPLE_AGE + std::time::Duration::from_millis(100),
+    );
+    for _ in 1..MAX_CONSECUTIVE_METER_FAILURES {
+        assert!(matches!(dryer.tick().await, TickOutcome::MeterUnavailable));
+        assert_eq!(relay.current_state(), RelayState::On);
+        assert!(dryer.active_cycle().is_some());
+    }
+    assert!(matches!(
+        dryer.tick().await,
+        TickOutcome::TurnedOffDueToMeterFailure { .. }
+    ));
+    assert_eq!(relay.current_state(), RelayState::Off);
+    assert!(dryer.active_cycle().is_none());
+    assert!(matches!(
+        dryer.cycle_updates.back(),
+        Some(CycleUpdate::Ended(_, EndReason::MeterFailure))
+    ));
+}
+
+#[tokio::test]
+async fn relay_failing_at_startup_is_a_fault_retried_on_tick() {
+    use dringos::relay::{MockRelay, RelayState};
+    let emulator = dringos::emulator::Emulator::with_manual_clock();
+    let relay = MockRelay::new();
+    // left on by whatever ran before
+    dringos::relay::Relay::turn_on(&mut relay.clone()).unwrap();
+    relay.set_failing(true);
+    let off = OffState::with_meter(dringos::pzemv3::Pzem::new(emulator.port()), relay.clone());
+    let mut dryer = DryerManager::with_machine(1, off);
+    assert!(dryer.relay_stuck());
+    assert!(matches!(
+        dryer.check_hardware(),
+        Some(HardwareFault::RelayNotOff { .. })
+    ));
+    assert!(matches!(dryer.tick().await, TickOutcome::Off));
+    assert!(dryer.relay_stuck());
+    assert_eq!(relay.current_state(), RelayState::On);
+    relay.set_failing(false);
+    assert!(matches!(dryer.tick().await, TickOutcome::Off));
+    assert!(!dryer.relay_stuck());
+    assert_eq!(relay.current_state(), RelayState::Off);
+}
+
+/// Cycle of user 42 saved `saved_s_ago` seconds ago, when the meter counter was at 1000Wh
+#[cfg(test)]
+fn saved_cycle(saved_s_ago: i64) -> crate::database::ActiveCycle {
+    let updated_at = chrono::Utc::now() - chrono::Duration::seconds(saved_s_ago);
+    crate::database::ActiveCycle {
+        machine: 1,
+        telegram_id: 42,
+        chat_id: 420,
+        name: "Fulano".to_string(),
+        balance: Money::from_centavos(945),
+        started_at: updated_at - chrono::Duration::minutes(30),
+        start_meter_energy_wh: 500,
+        last_meter_energy_wh: 1000,
+        consumed_wh: 500,
+        carried_millicentavos: 250,
+        charged: Money::from_centavos(55),
+        updated_at,
+    }
+}
+
+#[tokio::test]
+async fn recent_cycles_are_resumed() {
+    use dringos::relay::RelayState;
+    let (mut dryer, emulator, relay) = emulated_dryer();
+    emulator.set_energy_wh(1050);
+    let recovered = dryer.recover_cycle(saved_cycle(60)).await;
+    assert!(matches!(recovered, RecoveredCycle::Resumed(_)));
+    assert_eq!(relay.current_state(), RelayState::On);
+    // what the meter counted meanwhile is left to the next tick
+    assert_eq!(dryer.active_cycle().unwrap().last_meter_energy_wh, 1000);
+    assert!(matches!(
+        dryer.pending_cycle_update(),
+        Some(CycleUpdate::Active(_))
+    ));
+}
+
+#[tokio::test]
+async fn stale_cycles_are_closed_billing_the_downtime() {
+    use dringos::relay::RelayState;
+    let (mut dryer, emulator, relay) = emulated_dryer();
+    emulator.set_energy_wh(1050);
+    let recovered = dryer
+        .recover_cycle(saved_cycle(MAX_RESUME_DOWNTIME_SECONDS + 60))
+        .await;
+    let (cycle_stats, charge) = match recovered {
+        RecoveredCycle::Closed {
+            cycle_stats,
+            charge,
+            reason: EndReason::Restart,
+        } => (cycle_stats, charge),
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(relay.current_state(), RelayState::Off);
+    assert!(dryer.active_cycle().is_none());
+    // 50Wh at R$1.10/kWh is 5.5 centavos, plus the 0.25 carried
+    assert_eq!(charge, Money::from_centavos(5));
+    assert_eq!(cycle_stats.carried_millicentavos, 750);
+    assert_eq!(cycle_stats.consumed_wh, 550);
+    assert_eq!(cycle_stats.last_meter_energy_wh, 1050);
+    assert_eq!(cycle_stats.charged, Money::from_centavos(60));
+    // the charge couldn't be recorded, so the cycle is saved without it
+    dryer.charge_failed(charge);
+    match dryer.pending_cycle_update() {
+        Some(CycleUpdate::Ended(saved, EndReason::Restart)) => {
+            assert_eq!(saved.charged, Money::from_centavos(55));
+            assert_eq!(saved.consumed_wh, 550);
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn stale_cycles_are_closed_without_energy_if_the_meter_is_down() {
+    use dringos::emulator::Fault;
+    let (mut dryer, emulator, _) = emulated_dryer();
+    emulator.set_energy_wh(1050);
+    for _ in 0..100 {
+        emulator.inject(Fault::NoReply);
+    }
+    let recovered = dryer
+        .recover_cycle(saved_cycle(MAX_RESUME_DOWNTIME_SECONDS + 60))
+        .await;
+    match recovered {
+        RecoveredCycle::Closed {
+            cycle_stats,
+            charge,
+            reason: EndReason::RestartEnergyUnknown,
+        } => {
+            assert_eq!(charge, Money::ZERO);
+            assert_eq!(cycle_stats.consumed_wh, 500);
+            assert_eq!(cycle_stats.charged, Money::from_centavos(55));
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(matches!(
+        dryer.pending_cycle_update(),
+        Some(CycleUpdate::Ended(_, EndReason::RestartEnergyUnknown))
+    ));
+}
+
+#[tokio::test]
+async fn pending_cycle_updates_keep_only_the_latest_snapshot() {
+    let (mut dryer, _, _) = emulated_dryer();
+    let cycle_stats = CycleStats::restore(saved_cycle(0));
+    let mut newer = cycle_stats.clone();
+    newer.consumed_wh += 10;
+    dryer.queue_cycle_update(CycleUpdate::Active(cycle_stats.clone()));
+    dryer.queue_cycle_update(CycleUpdate::Active(newer));
+    assert_eq!(dryer.cycle_updates.len(), 1);
+    // an end is never dropped, whatever comes after it
+    dryer.queue_cycle_update(CycleUpdate::Ended(cycle_stats.clone(), EndReason::Idle));
+    dryer.queue_cycle_update(CycleUpdate::Active(cycle_stats));
+    assert_eq!(dryer.cycle_updates.len(), 3);
+    match dryer.pending_cycle_update() {
+        Some(CycleUpdate::Active(saved)) => assert_eq!(saved.consumed_wh, 510),
+        other => panic!("{:?}", other),
+    }
+    dryer.cycle_update_saved();
+    assert!(matches!(
+        dryer.pending_cycle_update(),
+        Some(CycleUpdate::Ended(_, EndReason::Idle))
+    ));
+}
@@ -0,0 +1,196 @@
+use crate::dryer_machine::OffState;
+use crate::dryer_manager::DryerManager;
+use crate::money::{EnergyPrice, Money};
+use crate::{Buttons, MsgType, OutgoingMessage};
+
+/// Every machine driven by this process, each by its own [`DryerManager`]. Users only see
+/// machine numbers when there is more than one.
+pub struct Dryers {
+    machines: Vec<DryerManager>,
+}
+
+impl Dryers {
+    /// The machines wired to this computer, see [`OffState::machines`]
+    pub fn new() -> Self {
+        let machines = OffState::machines()
+            .into_iter()
+            .enumerate()
+            .map(|(index, machine)| {
+                let number = u8::try_from(index + 1).expect("Too many machines configured");
+                DryerManager::with_machine(number, machine)
+            })
+            .collect();
+        Self::with_machines(machines)
+    }
+    /// Numbered in order, from 1
+    pub fn with_machines(machines: Vec<DryerManager>) -> Self {
+        assert!(!machines.is_empty(), "No machine to drive");
+        Self { machines }
+    }
+    pub fn count(&self) -> u8 {
+        u8::try_from(self.machines.len()).expect("Too many machines configured")
+    }
+    pub fn get(&self, number: u8) -> Option<&DryerManager> {
+        self.machines.get(usize::from(number).checked_sub(1)?)
+    }
+    pub fn get_mut(&mut self, number: u8) -> Option<&mut DryerManager> {
+        self.machines.get_mut(usize::from(number).checked_sub(1)?)
+    }
+    /// Goes before whatever is said about a machine, empty when there is only one
+    pub fn prefix(&self, number: u8) -> String {
+        if self.machines.len() > 1 {
+            format!("Secadora {}: ", number)
+        } else {
+            String::new()
+        }
+    }
+    /// The same for every machine
+    pub fn price(&self) -> EnergyPrice {
+        self.machines[0].price()
+    }
+    pub fn set_price(&mut self, price: EnergyPrice) {
+        for dryer in &mut self.machines {
+            dryer.set_price(price);
+        }
+    }
+    /// See [`DryerManager::balance_changed`]
+    pub fn balance_changed(&mut self, telegram_id: u64, balance: Money) {
+        for dryer in &mut self.machines {
+            dryer.balance_changed(telegram_id, balance);
+        }
+    }
+    /// Machine `telegram_id` is drying on, users dry on one machine at a time since every
+    /// machine bills the balance it knew when the cycle started
+    fn used_by(&self, telegram_id: u64) -> Option<u8> {
+        self.machines
+            .iter()
+            .find(|dryer| {
+                dryer
+                    .active_cycle()
+                    .is_some_and(|cycle_stats| cycle_stats.user.telegram_id == telegram_id)
+            })
+            .map(DryerManager::number)
+    }
+    /// Machine to turn on for `telegram_id`, the one asked for or else the first free one, or
+    /// what to answer if there is none
+    fn pick_machine(&self, telegram_id: u64, requested: Option<u8>) -> Result<u8, String> {
+        let using = self.used_by(telegram_id);
+        match (requested, using) {
+            (Some(number), _) if number == 0 || number > self.count() => {
+                Err(format!("Não existe a secadora {}.", number))
+            }
+            (Some(number), Some(using)) if number != using => {
+                Err(format!("Você já está usando a secadora {}.", using))
+            }
+            (Some(number), _) | (None, Some(number)) => Ok(number),
+            // it answers who is using it
+            (None, None) if self.machines.len() == 1 => Ok(1),
+            (None, None) => self
+                .machines
+                .iter()
+                .find(|dryer| dryer.active_cycle().is_none())
+                .map(DryerManager::number)
+                .ok_or_else(|| "Todas as secadoras estão em uso.".to_string()),
+        }
+    }
+    pub async fn handle_telegram_msg(
+        &mut self,
+        user_msg: super::telegram::UserMessage,
+        db_user: super::database::User,
+    ) -> OutgoingMessage {
+        let update_message_with_id = match user_msg.update {
+            MsgType::TurnOn { .. } | MsgType::Update => user_msg.message_id,
+            _ => None,
+        };
+        if let MsgType::TurnOn { machine } = user_msg.update {
+            let number = match self.pick_machine(user_msg.user_id, machine) {
+                Ok(number) => number,
+                Err(text) => {
+                    return OutgoingMessage {
+                        update_message_with_id,
+                        chat_id: user_msg.chat_id,
+                        text,
+                        buttons: Buttons::Dryer,
+                    }
+                }
+            };
+            let prefix = self.prefix(number);
+            let dryer = self.get_mut(number).expect("Picked a machine that exists");
+            let mut response = dryer.handle_telegram_msg(user_msg, db_user).await;
+            response.text.insert_str(0, &prefix);
+            return response;
+        }
+        if let [dryer] = &mut self.machines[..] {
+            return dryer.handle_telegram_msg(user_msg, db_user).await;
+        }
+        let mut lines = Vec::with_capacity(self.machines.len());
+        for dryer in &mut self.machines {
+            let status = dryer
+                .get_status_message(user_msg.user_id, db_user.balance)
+                .await;
+            lines.push(format!("Secadora {}: {}", dryer.number(), status));
+        }
+        OutgoingMessage {
+            update_message_with_id,
+            chat_id: user_msg.chat_id,
+            text: lines.join("\n"),
+            buttons: Buttons::Dryer,
+        }
+    }
+}
+
+#[tokio::test]
+async fn users_get_a_free_machine_one_at_a_time() {
+    use dringos::emulator::Emulator;
+    use dringos::relay::{MockRelay, RelayState};
+    let emulators = [Emulator::with_manual_clock(), Emulator::with_manual_clock()];
+    let relays = [MockRelay::new(), MockRelay::new()];
+    let machines = emulators
+        .iter()
+        .zip(&relays)
+        .zip(1..)
+        .map(|((emulator, relay), number)| {
+            let meter = dringos::pzemv3::Pzem::new(emulator.port());
+            DryerManager::with_machine(number, OffState::with_meter(meter, relay.clone()))
+        })
+        .collect();
+    let mut dryers = Dryers::with_machines(machines);
+    let turn_on_message = |telegram_id: u64, machine: Option<u8>| {
+        let user_msg = super::telegram::UserMessage {
+            message_id: None,
+            message_text: None,
+            user_id: telegram_id,
+            user_name: "fulano".to_string(),
+            chat_id: telegram_id as i64,
+            update: MsgType::TurnOn { machine },
+        };
+        let db_user = super::database::User {
+            telegram_id: telegram_id as i64,
+            name: format!("Usuário {}", telegram_id),
+            balance: Money::from_centavos(1000),
+            is_admin: false,
+            blocked: false,
+        };
+        (user_msg, db_user)
+    };
+    let (user_msg, db_user) = turn_on_message(42, None);
+    let response = dryers.handle_telegram_msg(user_msg, db_user).await;
+    assert!(response.text.starts_with("Secadora 1: Ligada"));
+    let (user_msg, db_user) = turn_on_message(42, Some(2));
+    let response = dryers.handle_telegram_msg(user_msg, db_user).await;
+    assert_eq!(response.text, "Você já está usando a secadora 1.");
+    let (user_msg, db_user) = turn_on_message(43, None);
+    let response = dryers.handle_telegram_msg(user_msg, db_user).await;
+    assert!(response.text.starts_with("Secadora 2: Ligada"));
+    assert_eq!(relays[1].current_state(), RelayState::On);
+    let (user_msg, db_user) = turn_on_message(44, None);
+    let response = dryers.handle_telegram_msg(user_msg, db_user).await;
+    assert_eq!(response.text, "Todas as secadoras estão em uso.");
+    let (user_msg, db_user) = turn_on_message(44, Some(3));
+    let response = dryers.handle_telegram_msg(user_msg, db_user).await;
+    assert_eq!(response.text, "Não existe a secadora 3.");
+    assert_eq!(
+        dryers.get_mut(2).unwrap().active_cycle().unwrap().machine,
+        2
+    );
+}
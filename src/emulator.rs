@@ -0,0 +1,510 @@
+//! Software PZEM-004T v3 speaking real Modbus RTU, so the drivers and everything built on top
+//! of them can run without a meter plugged in.
+//!
+//! The [`Emulator`] is a handle to the simulated meter, used to script what it measures and
+//! which faults it shows, while [`EmulatorPort`] (in-memory) or [`Emulator::spawn_pty`]
+//! (a pseudo terminal, for code that wants a real serial port) are what the driver talks to.
+
+use crate::meter::ENERGY_COUNTER_ROLLOVER_WH;
+use crate::modbus::{
+    crc_is_valid, crc_write, ExceptionCode, EXCEPTION_FLAG, READ_HOLDING_REGISTERS,
+    READ_INPUT_REGISTERS, WRITE_SINGLE_REGISTER,
+};
+use crate::pzemv3::ADDRESS_RANGE;
+use crate::transport::Transport;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const RESET_ENERGY: u8 = 0x42;
+const GENERAL_ADDRESS: u8 = 0xF8;
+const BROADCAST_ADDRESS: u8 = 0x00;
+const ENERGY_ROLLOVER_WH: f64 = ENERGY_COUNTER_ROLLOVER_WH as f64;
+
+/// Misbehavior to show on the next requests, one fault is consumed per request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Reply with a broken CRC
+    CorruptCrc,
+    /// Ignore the request and don't reply, the driver sees a timeout
+    NoReply,
+    /// Execute the request but lose the reply, the driver sees a timeout
+    DropReply,
+    /// Reply with the given exception instead of the data
+    Exception(ExceptionCode),
+}
+
+/// Power drawn by the dryer as a function of the emulator time
+pub type PowerCurve = Box<dyn FnMut(Duration) -> f32 + Send>;
+
+struct Device {
+    address: u8,
+    power_alarm_threshold_w: u16,
+    voltage: f32,
+    frequency: f32,
+    power_factor: f32,
+    power_curve: PowerCurve,
+    power_w: f32,
+    energy_wh: f64,
+    elapsed: Duration,
+    /// `None` when time only moves through [`Emulator::advance`]
+    real_time_since: Option<Instant>,
+    faults: VecDeque<Fault>,
+}
+
+impl Device {
+    /// Moves the emulator clock, integrating the power curve in small steps
+    fn advance(&mut self, duration: Duration) {
+        let step = Duration::from_millis(100);
+        let mut remaining = duration;
+        while !remaining.is_zero() {
+            let dt = remaining.min(step);
+            self.power_w = (self.power_curve)(self.elapsed).max(0.);
+            self.energy_wh += self.power_w as f64 * dt.as_secs_f64() / 3600.;
+            if self.energy_wh >= ENERGY_ROLLOVER_WH {
+                self.energy_wh -= ENERGY_ROLLOVER_WH;
+            }
+            self.elapsed += dt;
+            remaining -= dt;
+        }
+        self.power_w = (self.power_curve)(self.elapsed).max(0.);
+    }
+
+    fn catch_up_real_time(&mut self) {
+        if let Some(since) = self.real_time_since {
+            let now = Instant::now();
+            self.advance(now - since);
+            self.real_time_since = Some(now);
+        }
+    }
+
+    fn input_registers(&self) -> [u16; 10] {
+        let current = if self.voltage > 0. && self.power_factor > 0. {
+            self.power_w / (self.voltage * self.power_factor)
+        } else {
+            0.
+        };
+        let current_ma = (current * 1000.).round() as u32;
+        let power_dw = (self.power_w * 10.).round() as u32;
+        let energy_wh = self.energy_wh as u32;
+        let alarm = if self.power_w > f32::from(self.power_alarm_threshold_w) {
+            0xFFFF
+        } else {
+            0x0000
+        };
+        // 32 bit values are sent low word first
+        [
+            (self.voltage * 10.).round() as u16,
+            current_ma as u16,
+            (current_ma >> 16) as u16,
+            power_dw as u16,
+            (power_dw >> 16) as u16,
+            energy_wh as u16,
+            (energy_wh >> 16) as u16,
+            (self.frequency * 10.).round() as u16,
+            (self.power_factor * 100.).round() as u16,
+            alarm,
+        ]
+    }
+
+    /// Answers one request frame like the meter would, `None` means staying silent
+    fn handle_frame(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        if frame.len() < 4 || !crc_is_valid(frame) {
+            log::debug!("Emulator ignoring corrupt frame {:02x?}", frame);
+            return None;
+        }
+        let slave = frame[0];
+        if slave != self.address && slave != GENERAL_ADDRESS && slave != BROADCAST_ADDRESS {
+            return None;
+        }
+        self.catch_up_real_time();
+        let function = frame[1];
+        let fault = self.faults.pop_front();
+        let result = match fault {
+            Some(Fault::NoReply) => return None,
+            Some(Fault::Exception(code)) => Err(code),
+            Some(Fault::CorruptCrc) | Some(Fault::DropReply) | None => {
+                self.execute(function, &frame[2..frame.len() - 2])
+            }
+        };
+        if slave == BROADCAST_ADDRESS || fault == Some(Fault::DropReply) {
+            return None;
+        }
+        // replies come from the address the request was sent to, even when changing it
+        let mut reply = match result {
+            Ok(payload) => {
+                let mut reply = vec![slave, function];
+                reply.extend(payload);
+                reply
+            }
+            Err(code) => vec![slave, function | EXCEPTION_FLAG, u8::from(code)],
+        };
+        reply.extend_from_slice(&[0, 0]);
+        crc_write(&mut reply);
+        if fault == Some(Fault::CorruptCrc) {
+            let n = reply.len();
+            reply[n - 1] ^= 0xFF;
+        }
+        Some(reply)
+    }
+
+    /// Executes a request payload, returns what follows the function code in the reply
+    fn execute(&mut self, function: u8, data: &[u8]) -> Result<Vec<u8>, ExceptionCode> {
+        let word = |i: usize| -> Result<u16, ExceptionCode> {
+            data.get(i..i + 2)
+                .map(|w| u16::from_be_bytes([w[0], w[1]]))
+                .ok_or(ExceptionCode::IllegalData)
+        };
+        match function {
+            READ_INPUT_REGISTERS | READ_HOLDING_REGISTERS => {
+                let start = word(0)? as usize;
+                let count = word(2)? as usize;
+                let registers: Vec<u16> = if function == READ_INPUT_REGISTERS {
+                    self.input_registers().to_vec()
+                } else {
+                    // holding registers start at 0x0001
+                    vec![0, self.power_alarm_threshold_w, u16::from(self.address)]
+                };
+                let first_valid = if function == READ_INPUT_REGISTERS {
+                    0
+                } else {
+                    1
+                };
+                if count == 0 || start < first_valid || start + count > registers.len() {
+                    return Err(ExceptionCode::IllegalAddress);
+                }
+                let mut payload = vec![(count * 2) as u8];
+                for r in &registers[start..start + count] {
+                    payload.extend_from_slice(&r.to_be_bytes());
+                }
+                Ok(payload)
+            }
+            WRITE_SINGLE_REGISTER => {
+                let register = word(0)?;
+                let value = word(2)?;
+                match register {
+                    0x0001 => self.power_alarm_threshold_w = value,
+                    0x0002 => {
+                        let address = u8::try_from(value)
+                            .ok()
+                            .filter(|a| ADDRESS_RANGE.contains(a))
+                            .ok_or(ExceptionCode::IllegalData)?;
+                        self.address = address;
+                    }
+                    _ => return Err(ExceptionCode::IllegalAddress),
+                }
+                Ok(data.to_vec())
+            }
+            RESET_ENERGY => {
+                self.energy_wh = 0.;
+                Ok(vec![])
+            }
+            _ => Err(ExceptionCode::IllegalFunction),
+        }
+    }
+}
+
+/// Handle to a simulated meter, cheap to clone and usable while the driver owns the port
+#[derive(Clone)]
+pub struct Emulator {
+    device: Arc<Mutex<Device>>,
+}
+
+impl std::fmt::Debug for Emulator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Emulator")
+            .field("address", &self.address())
+            .field("energy_wh", &self.energy_wh())
+            .finish()
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    /// Meter at the default address with no load, its clock follows the wall clock
+    pub fn new() -> Self {
+        let device = Device {
+            address: crate::pzemv3::DEFAULT_ADDRESS,
+            power_alarm_threshold_w: 2300,
+            voltage: 220.,
+            frequency: 60.,
+            power_factor: 1.,
+            power_curve: Box::new(|_| 0.),
+            power_w: 0.,
+            energy_wh: 0.,
+            elapsed: Duration::ZERO,
+            real_time_since: Some(Instant::now()),
+            faults: VecDeque::new(),
+        };
+        Self {
+            device: Arc::new(Mutex::new(device)),
+        }
+    }
+
+    /// Same as [`Emulator::new`] but time only moves through [`Emulator::advance`]
+    pub fn with_manual_clock() -> Self {
+        let emulator = Self::new();
+        emulator.device().real_time_since = None;
+        emulator
+    }
+
+    fn device(&self) -> std::sync::MutexGuard<'_, Device> {
+        self.device.lock().expect("Emulator lock poisoned")
+    }
+
+    /// In-memory port to give to the driver
+    pub fn port(&self) -> EmulatorPort {
+        EmulatorPort {
+            device: self.device.clone(),
+            tx: Vec::new(),
+            rx: VecDeque::new(),
+        }
+    }
+
+    pub fn set_power_curve<F: FnMut(Duration) -> f32 + Send + 'static>(&self, curve: F) {
+        let mut device = self.device();
+        device.catch_up_real_time();
+        device.power_curve = Box::new(curve);
+        let elapsed = device.elapsed;
+        device.power_w = (device.power_curve)(elapsed).max(0.);
+    }
+
+    pub fn set_power_w(&self, power_w: f32) {
+        self.set_power_curve(move |_| power_w);
+    }
+
+    pub fn set_voltage(&self, voltage: f32) {
+        self.device().voltage = voltage;
+    }
+
+    pub fn set_energy_wh(&self, energy_wh: u32) {
+        self.device().energy_wh = f64::from(energy_wh);
+    }
+
+    /// Moves the emulator clock forward, accumulating energy from the power curve
+    pub fn advance(&self, duration: Duration) {
+        self.device().advance(duration);
+    }
+
+    /// Queues a fault, shown on the next request addressed to this meter
+    pub fn inject(&self, fault: Fault) {
+        self.device().faults.push_back(fault);
+    }
+
+    pub fn address(&self) -> u8 {
+        self.device().address
+    }
+
+    pub fn power_alarm_threshold_w(&self) -> u16 {
+        self.device().power_alarm_threshold_w
+    }
+
+    pub fn energy_wh(&self) -> u32 {
+        self.device().energy_wh as u32
+    }
+
+    /// Serves the emulator on a pseudo terminal from a background thread and returns the
+    /// other end, to be used as a regular serial port. The thread exits once it's dropped.
+    #[cfg(unix)]
+    pub fn spawn_pty(&self) -> std::io::Result<serialport::TTYPort> {
+        use serialport::SerialPort;
+        let (mut master, mut slave) = serialport::TTYPort::pair()?;
+        slave.set_timeout(Duration::from_millis(200))?;
+        let device = self.device.clone();
+        std::thread::Builder::new()
+            .name("PzemEmulator".to_string())
+            .spawn(move || loop {
+                match read_request_frame(&mut master) {
+                    Ok(frame) => {
+                        let reply = device
+                            .lock()
+                            .expect("Emulator lock poisoned")
+                            .handle_frame(&frame);
+                        if let Some(reply) = reply {
+                            if let Err(e) = std::io::Write::write_all(&mut master, &reply) {
+                                log::warn!("Emulator stopping: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                    Err(e) => {
+                        log::debug!("Emulator stopping: {}", e);
+                        break;
+                    }
+                }
+            })?;
+        Ok(slave)
+    }
+}
+
+/// Reads one request from the line, requests have a fixed size given their function code
+#[cfg(unix)]
+fn read_request_frame(port: &mut serialport::TTYPort) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut frame = vec![0; 2];
+    port.read_exact(&mut frame)?;
+    let total_len = match frame[1] {
+        READ_INPUT_REGISTERS | READ_HOLDING_REGISTERS | WRITE_SINGLE_REGISTER => 8,
+        RESET_ENERGY => 4,
+        // calibration: 0xF8 0x41 0x37 0x21 CRC CRC
+        0x41 => 6,
+        _ => 2,
+    };
+    frame.resize(total_len, 0);
+    port.read_exact(&mut frame[2..])?;
+    Ok(frame)
+}
+
+/// In-memory end of the line connected to an [`Emulator`]
+pub struct EmulatorPort {
+    device: Arc<Mutex<Device>>,
+    tx: Vec<u8>,
+    rx: VecDeque<u8>,
+}
+
+impl std::fmt::Debug for EmulatorPort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmulatorPort")
+            .field("tx", &self.tx)
+            .field("rx", &self.rx)
+            .finish()
+    }
+}
+
+impl Transport for EmulatorPort {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.tx.extend_from_slice(buf);
+        Ok(())
+    }
+
+    /// The whole frame was written, the meter gets to answer it
+    fn flush(&mut self) -> std::io::Result<()> {
+        let frame = std::mem::take(&mut self.tx);
+        if frame.is_empty() {
+            return Ok(());
+        }
+        let reply = self
+            .device
+            .lock()
+            .expect("Emulator lock poisoned")
+            .handle_frame(&frame);
+        if let Some(reply) = reply {
+            self.rx.extend(reply);
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        if self.rx.len() < buf.len() {
+            self.rx.clear();
+            return Err(Error::new(ErrorKind::TimedOut, "Operation timed out"));
+        }
+        for b in buf.iter_mut() {
+            *b = self.rx.pop_front().expect("Checked length above");
+        }
+        Ok(())
+    }
+
+    fn discard_input(&mut self) -> std::io::Result<usize> {
+        let n = self.rx.len();
+        self.rx.clear();
+        Ok(n)
+    }
+}
+
+#[test]
+fn driver_reads_and_configures_emulated_meter() {
+    use crate::error::Error as PzemError;
+    use crate::pzemv3::Pzem;
+    let emulator = Emulator::with_manual_clock();
+    emulator.set_power_curve(|t| {
+        if t < Duration::from_secs(60) {
+            2200.
+        } else {
+            0.
+        }
+    });
+    emulator.advance(Duration::from_secs(30 * 60));
+    let mut pzem = Pzem::new(emulator.port());
+    let data = pzem.read_data().unwrap();
+    assert_eq!(data.power_w, 0.);
+    // 2200W for one minute
+    assert_eq!(data.energy_wh, 36);
+
+    emulator.inject(Fault::CorruptCrc);
+    emulator.inject(Fault::NoReply);
+    emulator.inject(Fault::Exception(ExceptionCode::SlaveError));
+    assert!(matches!(pzem.read_data(), Err(PzemError::Crc)));
+    assert!(matches!(pzem.read_data(), Err(PzemError::Timeout)));
+    assert!(matches!(
+        pzem.read_data(),
+        Err(PzemError::Exception(ExceptionCode::SlaveError))
+    ));
+
+    pzem.set_power_alarm_threshold_w(1000).unwrap();
+    assert_eq!(pzem.read_power_alarm_threshold_w().unwrap(), 1000);
+    pzem.set_address(0x07).unwrap();
+    assert_eq!(emulator.address(), 0x07);
+    assert_eq!(pzem.read_address().unwrap(), 0x07);
+}
+
+#[test]
+fn energy_reset_is_verified_and_retried() {
+    use crate::pzemv3::{Pzem, ResetOutcome, RetryPolicy};
+    let emulator = Emulator::with_manual_clock();
+    emulator.set_energy_wh(1234);
+    let mut pzem = Pzem::new(emulator.port());
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+    };
+    // reset applied but its reply lost, the read back shows it worked
+    emulator.inject(Fault::DropReply);
+    assert!(matches!(
+        pzem.reset_energy_with_retries(&policy),
+        ResetOutcome::Confirmed { attempts: 1 }
+    ));
+    assert_eq!(emulator.energy_wh(), 0);
+
+    emulator.set_energy_wh(1234);
+    emulator.inject(Fault::NoReply);
+    emulator.inject(Fault::CorruptCrc);
+    assert!(matches!(
+        pzem.reset_energy_with_retries(&policy),
+        ResetOutcome::Confirmed { attempts: 2 }
+    ));
+
+    emulator.set_energy_wh(1234);
+    for _ in 0..3 {
+        emulator.inject(Fault::NoReply);
+        emulator.inject(Fault::NoReply);
+    }
+    assert!(matches!(
+        pzem.reset_energy_with_retries(&policy),
+        ResetOutcome::GaveUp {
+            attempts: 3,
+            last_error: crate::error::Error::Timeout
+        }
+    ));
+    assert_eq!(emulator.energy_wh(), 1234);
+}
+
+#[cfg(unix)]
+#[test]
+fn emulator_serves_a_pseudo_terminal() {
+    let emulator = Emulator::new();
+    emulator.set_power_w(1500.);
+    let port: Box<dyn serialport::SerialPort> = Box::new(emulator.spawn_pty().unwrap());
+    let mut pzem = crate::pzemv3::Pzem::new(port);
+    let data = pzem.read_data().unwrap();
+    assert_eq!(data.power_w, 1500.);
+    assert_eq!(data.voltage, 220.);
+}
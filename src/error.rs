@@ -0,0 +1,82 @@
+use crate::modbus::ExceptionCode;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+
+/// Everything that can go wrong talking to a PZEM
+#[derive(Debug)]
+pub enum Error {
+    /// No reply, or only part of one, before the read timeout expired
+    Timeout,
+    /// A reply arrived but its CRC (or checksum for v1 meters) doesn't match its content
+    Crc,
+    /// The meter understood the request and refused it
+    Exception(ExceptionCode),
+    /// The reply doesn't have the size the request asked for
+    UnexpectedLength { expected: usize, actual: usize },
+    /// A well formed reply that doesn't answer our request, like one from another slave
+    UnexpectedReply(String),
+    /// The request was refused before reaching the line, like an out of range address
+    InvalidArgument(String),
+    /// The serial port or socket itself failed
+    Transport(std::io::Error),
+    /// The meter model can't do what was asked
+    Unsupported(&'static str),
+}
+
+impl Error {
+    /// True if trying again has a fair chance of working, line noise and a meter too busy to
+    /// answer are retryable while a port that is gone or a request the meter refuses are not.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Timeout
+            | Error::Crc
+            | Error::UnexpectedLength { .. }
+            | Error::UnexpectedReply(_) => true,
+            Error::Exception(code) => *code == ExceptionCode::SlaveError,
+            Error::InvalidArgument(_) | Error::Transport(_) | Error::Unsupported(_) => false,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "Pzem did not reply in time"),
+            Error::Crc => write!(f, "Pzem returned an invalid CRC value"),
+            Error::Exception(code) => write!(f, "Pzem returned exception: {}", code),
+            Error::UnexpectedLength { expected, actual } => write!(
+                f,
+                "Pzem returned a different amount of data than requested. Should have been {} bytes, was: {}",
+                expected, actual
+            ),
+            Error::UnexpectedReply(msg) => write!(f, "Unexpected Pzem reply: {}", msg),
+            Error::InvalidArgument(msg) => write!(f, "Invalid Pzem request: {}", msg),
+            Error::Transport(e) => write!(f, "Pzem transport error: {}", e),
+            Error::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Transport(e),
+        }
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(e: serialport::Error) -> Self {
+        Error::from(std::io::Error::from(e))
+    }
+}
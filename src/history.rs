@@ -0,0 +1,141 @@
+use crate::database::{Cycle, Database, MonthlyUsage, Transaction, TransactionKind};
+use crate::dryer_manager::EndReason;
+use chrono::Local;
+
+/// Cycles or transactions shown when the user doesn't say how many
+pub const DEFAULT_ENTRIES: u8 = 5;
+pub const MAX_ENTRIES: u8 = 20;
+/// Months of totals shown after the cycles
+const MONTHS: i64 = 6;
+
+/// The last `cycles` cycles of a user and their monthly totals
+pub async fn report(database: &Database, telegram_id: u64, cycles: u8) -> String {
+    let last_cycles = database.last_cycles(telegram_id, i64::from(cycles)).await;
+    let monthly_usage = database.monthly_usage(telegram_id, MONTHS).await;
+    match (last_cycles, monthly_usage) {
+        (Ok(last_cycles), Ok(monthly_usage)) => format_report(&last_cycles, &monthly_usage),
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("{:#?}", e);
+            "Problema na rede interna da casa, fale com @TiberioFerreira".to_string()
+        }
+    }
+}
+
+fn format_report(last_cycles: &[Cycle], monthly_usage: &[MonthlyUsage]) -> String {
+    if last_cycles.is_empty() {
+        return "Você ainda não usou a secadora.".to_string();
+    }
+    let mut report = format!("Últimos {} ciclos:\n", last_cycles.len());
+    for cycle in last_cycles {
+        let duration_s = (cycle.ended_at - cycle.started_at).num_seconds().max(0) as u64;
+        let reason = match cycle.end_reason.parse::<EndReason>() {
+            Ok(reason) => reason.description(),
+            Err(_) => cycle.end_reason.as_str(),
+        };
+        report += &format!(
+            "{start} - {duration}, {kwh:.2} kwh, R${cost} ({reason})\n",
+            start = cycle.started_at.with_timezone(&Local).format("%d/%m %H:%M"),
+            duration = crate::seconds_to_hour_format(duration_s),
+            kwh = cycle.energy_wh as f64 / 1000.,
+            cost = cycle.cost,
+            reason = reason
+        );
+    }
+    report += "\nTotais por mês:\n";
+    for month in monthly_usage {
+        report += &format!(
+            "{month}: {cycles} ciclos, {kwh:.2} kwh, R${cost}\n",
+            month = month.month.with_timezone(&Local).format("%m/%Y"),
+            cycles = month.cycles,
+            kwh = month.energy_wh as f64 / 1000.,
+            cost = month.cost
+        );
+    }
+    report.trim_end().to_string()
+}
+
+/// The last `entries` balance transactions of a user
+pub async fn statement(database: &Database, telegram_id: u64, entries: u8) -> String {
+    match database
+        .last_transactions(telegram_id, i64::from(entries))
+        .await
+    {
+        Ok(transactions) => format_statement(&transactions),
+        Err(e) => {
+            log::error!("{:#?}", e);
+            "Problema na rede interna da casa, fale com @TiberioFerreira".to_string()
+        }
+    }
+}
+
+/// `transactions` most recent first
+fn format_statement(transactions: &[Transaction]) -> String {
+    if transactions.is_empty() {
+        return "Nenhuma movimentação no seu saldo.".to_string();
+    }
+    let mut statement = format!("Últimas {} movimentações:\n", transactions.len());
+    for transaction in transactions {
+        let kind = match transaction.kind.parse::<TransactionKind>() {
+            Ok(kind) => kind.description(),
+            Err(_) => transaction.kind.as_str(),
+        };
+        statement += &format!(
+            "{date} - {kind}: {amount:+} ({description}), saldo R${balance}\n",
+            date = transaction
+                .created_at
+                .with_timezone(&Local)
+                .format("%d/%m %H:%M"),
+            kind = kind,
+            amount = transaction.amount,
+            description = transaction.description,
+            balance = transaction.balance_after
+        );
+    }
+    statement.trim_end().to_string()
+}
+
+#[test]
+fn report_lists_cycles_and_months() {
+    use crate::money::Money;
+    use chrono::{Duration, TimeZone, Utc};
+    let started_at = Utc.ymd(2022, 3, 10).and_hms(12, 0, 0);
+    let cycles = [Cycle {
+        started_at,
+        ended_at: started_at + Duration::seconds(3700),
+        energy_wh: 1520,
+        cost: Money::from_centavos(167),
+        end_reason: "idle".to_string(),
+    }];
+    let months = [MonthlyUsage {
+        month: Utc.ymd(2022, 3, 1).and_hms(12, 0, 0),
+        cycles: 3,
+        energy_wh: 4500,
+        cost: Money::from_centavos(495),
+    }];
+    let report = format_report(&cycles, &months);
+    assert!(report.contains("1h1m40s, 1.52 kwh, R$1.67 (secadora parada)"));
+    assert!(report.ends_with("03/2022: 3 ciclos, 4.50 kwh, R$4.95"));
+    assert_eq!(format_report(&[], &[]), "Você ainda não usou a secadora.");
+}
+
+#[test]
+fn statement_lists_transactions() {
+    use crate::money::Money;
+    use chrono::{TimeZone, Utc};
+    let transaction = |kind: &str, amount, balance_after, description: &str| Transaction {
+        kind: kind.to_string(),
+        amount: Money::from_centavos(amount),
+        balance_after: Money::from_centavos(balance_after),
+        description: description.to_string(),
+        created_at: Utc.ymd(2022, 3, 10).and_hms(12, 0, 0),
+    };
+    let transactions = [
+        transaction("cycle_charge", -3, 997, "Ciclo iniciado em 10/03 11:00"),
+        transaction("top_up", 1000, 1000, "Pix"),
+    ];
+    let statement = format_statement(&transactions);
+    assert!(statement.starts_with("Últimas 2 movimentações:"));
+    assert!(statement.contains("secagem: -0.03 (Ciclo iniciado em 10/03 11:00), saldo R$9.97"));
+    assert!(statement.contains("recarga: +10.00 (Pix), saldo R$10.00"));
+    assert_eq!(format_statement(&[]), "Nenhuma movimentação no seu saldo.");
+}
@@ -0,0 +1,80 @@
+//! Blocking devices served from their own thread.
+//!
+//! The meter drivers do serial I/O with timeouts in the hundreds of milliseconds and smart plugs
+//! are switched over HTTP with timeouts of seconds, so instead of calling them from the runtime
+//! each device is moved to a dedicated thread that runs requests on it one at a time.
+
+use tokio::sync::{mpsc, oneshot};
+
+type Job<D> = Box<dyn FnOnce(&mut D) + Send>;
+
+/// Async handle to a device living in its own I/O thread. Handles are cheap to clone and all
+/// share the same device, the thread ends when every one of them has been dropped.
+pub struct IoThread<D> {
+    jobs: mpsc::Sender<Job<D>>,
+}
+
+impl<D> Clone for IoThread<D> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+        }
+    }
+}
+
+impl<D> std::fmt::Debug for IoThread<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IoThread")
+            .field("closed", &self.jobs.is_closed())
+            .finish()
+    }
+}
+
+impl<D: Send + 'static> IoThread<D> {
+    /// The thread is named after the device, `Meter` runs in `MeterIO`
+    pub fn spawn(name: &str, mut device: D) -> Self {
+        let (jobs, mut receiver) = mpsc::channel::<Job<D>>(16);
+        let thread_name = name.to_string();
+        std::thread::Builder::new()
+            .name(format!("{}IO", name))
+            .spawn(move || {
+                while let Some(job) = receiver.blocking_recv() {
+                    job(&mut device);
+                }
+                log::info!("{} I/O thread stopping", thread_name);
+            })
+            .unwrap_or_else(|e| panic!("Couldn't start {} I/O thread: {}", name, e));
+        Self { jobs }
+    }
+
+    /// Runs `request` on the device and waits for its result, `None` if the thread is gone
+    pub async fn run<T: Send + 'static>(
+        &self,
+        request: impl FnOnce(&mut D) -> T + Send + 'static,
+    ) -> Option<T> {
+        let (reply, response) = oneshot::channel();
+        let job: Job<D> = Box::new(move |device| {
+            // the requester may have given up waiting, nothing to do about it then
+            let _ = reply.send(request(device));
+        });
+        self.jobs.send(job).await.ok()?;
+        response.await.ok()
+    }
+}
+
+#[tokio::test]
+async fn requests_run_on_the_device_thread() {
+    let io = IoThread::spawn("Counter", 0_u32);
+    let other_handle = io.clone();
+    assert_eq!(
+        io.run(|count| {
+            *count += 1;
+            std::thread::current().name().map(str::to_string)
+        })
+        .await
+        .unwrap()
+        .as_deref(),
+        Some("CounterIO")
+    );
+    assert_eq!(other_handle.run(|count| *count).await, Some(1));
+}
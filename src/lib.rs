@@ -1,2 +1,10 @@
+pub mod emulator;
+pub mod error;
+pub mod io_thread;
+pub mod meter;
+pub mod modbus;
 pub mod pzemv1;
 pub mod pzemv3;
+pub mod relay;
+pub mod serial;
+pub mod transport;
@@ -1,13 +1,21 @@
-use crate::dryer_manager::TickOutcome;
-use crate::telegram::{MsgType, OutgoingMessage};
+use crate::dryer_manager::{
+    CycleStats, CycleUpdate, DryerManager, EndReason, RecoveredCycle, TickOutcome,
+};
+use crate::money::Money;
+use crate::telegram::{Buttons, MsgType, OutgoingMessage};
 use chrono::Timelike;
 use flexi_logger::{Age, Logger};
 use std::sync::mpsc::RecvTimeoutError;
 use std::time::Duration;
 
+mod admin;
 mod database;
 mod dryer_machine;
 mod dryer_manager;
+mod dryers;
+mod history;
+mod money;
+mod registration;
 mod telegram;
 
 fn seconds_to_hour_format(total_seconds: u64) -> String {
@@ -26,6 +34,362 @@ fn seconds_to_hours_works() {
     assert_eq!("1h6m40s", seconds_to_hour_format(4000));
 }
 
+/// Added to what users are told when their cycle ends if the relay refused to open, the
+/// admins are alerted through the hardware checks
+fn relay_warning(dryer: &DryerManager) -> &'static str {
+    if dryer.relay_stuck() {
+        " Atenção: a secadora não desligou e pode continuar ligada, os administradores foram avisados."
+    } else {
+        ""
+    }
+}
+
+/// Chats alerted when something needs a human, from the comma separated `ADMIN_CHAT_IDS`.
+/// Being in one of them doesn't make anyone an admin, a group may have other members.
+fn admin_chat_ids() -> Vec<i64> {
+    match std::env::var("ADMIN_CHAT_IDS") {
+        Ok(ids) => parse_ids(&ids, "admin chat"),
+        Err(_) => {
+            log::warn!("ADMIN_CHAT_IDS not set, no one will be alerted about failures");
+            vec![]
+        }
+    }
+}
+
+/// Users whose commands are admin commands, from the comma separated `ADMIN_USER_IDS`, besides
+/// those flagged as admins in the database
+fn admin_user_ids() -> Vec<u64> {
+    match std::env::var("ADMIN_USER_IDS") {
+        Ok(ids) => parse_ids(&ids, "admin user"),
+        Err(_) => vec![],
+    }
+}
+
+/// Invalid ids are logged and skipped
+fn parse_ids<T>(ids: &str, what: &str) -> Vec<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    ids.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .filter_map(|id| match id.parse() {
+            Ok(id) => Some(id),
+            Err(e) => {
+                log::error!("Invalid {} id `{}`: {}", what, id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Sends `text` to every admin chat
+fn alert_admins(
+    telegram_sender: &std::sync::mpsc::SyncSender<OutgoingMessage>,
+    admin_chat_ids: &[i64],
+    text: &str,
+) {
+    for admin_chat_id in admin_chat_ids {
+        let alert = OutgoingMessage {
+            update_message_with_id: None,
+            chat_id: *admin_chat_id,
+            text: text.to_string(),
+            buttons: Buttons::None,
+        };
+        if let Err(e) = telegram_sender.try_send(alert) {
+            log::error!("{:#?}", e);
+        }
+    }
+}
+
+/// How the charges of a cycle show up in the ledger
+fn cycle_charge_description(cycle_stats: &CycleStats) -> String {
+    format!(
+        "Ciclo iniciado em {}",
+        cycle_stats
+            .started_at
+            .with_timezone(&chrono::Local)
+            .format("%d/%m %H:%M")
+    )
+}
+
+/// Saves whatever changed in the active cycle so it can be recovered after a restart, in
+/// order. What fails to save is kept and retried on the next call.
+async fn persist_cycle_update(database: &database::Database, dryer: &mut DryerManager) {
+    while let Some(update) = dryer.pending_cycle_update() {
+        let result = match update {
+            CycleUpdate::Active(cycle_stats) => database.save_active_cycle(cycle_stats).await,
+            CycleUpdate::Ended(cycle_stats, reason) => {
+                database.end_cycle(cycle_stats, *reason).await
+            }
+        };
+        match result {
+            Ok(()) => dryer.cycle_update_saved(),
+            Err(e) => {
+                log::error!("Error saving the active cycle, will retry: {:#?}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// The private chats of admins, configured or flagged in the database, get the admin commands
+/// in their menu
+async fn register_commands(
+    database: &database::Database,
+    telegram_sender: &telegram::Sender,
+    admin_user_ids: &[u64],
+) {
+    let mut chat_ids: Vec<i64> = admin_user_ids
+        .iter()
+        .filter_map(|id| i64::try_from(*id).ok())
+        .collect();
+    match database.list_users().await {
+        Ok(users) => chat_ids.extend(
+            users
+                .iter()
+                .filter(|user| user.is_admin)
+                .map(|user| user.telegram_id),
+        ),
+        Err(e) => log::error!("Error listing the admin users: {:#?}", e),
+    }
+    chat_ids.sort_unstable();
+    chat_ids.dedup();
+    if let Err(e) = telegram_sender.register_commands(&chat_ids) {
+        log::error!("Error registering the command menu: {:#?}", e);
+    }
+}
+
+/// Resumes or closes a cycle interrupted by a restart, telling its user and, if something
+/// couldn't be billed, the admins
+async fn recover_cycle(
+    database: &database::Database,
+    telegram_sender: &std::sync::mpsc::SyncSender<OutgoingMessage>,
+    admin_chat_ids: &[i64],
+    dryer: &mut DryerManager,
+    prefix: &str,
+    saved: database::ActiveCycle,
+) {
+    let text = match dryer.recover_cycle(saved).await {
+        RecoveredCycle::Resumed(cycle_stats) => OutgoingMessage {
+            update_message_with_id: None,
+            chat_id: cycle_stats.user.chat_id,
+            text: format!(
+                "{prefix}O sistema da secadora foi reiniciado e o seu ciclo foi retomado. Secando há {cycle_time}, custo até o momento: R${cost}.",
+                prefix=prefix,
+                cycle_time=seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
+                cost=cycle_stats.charged,
+            ),
+            buttons: Buttons::Dryer,
+        },
+        RecoveredCycle::Closed {
+            mut cycle_stats,
+            charge,
+            reason,
+        } => {
+            if charge > Money::ZERO {
+                match database
+                    .charge_cycle(
+                        &cycle_stats,
+                        charge,
+                        &cycle_charge_description(&cycle_stats),
+                    )
+                    .await
+                {
+                    Ok(new_balance) => cycle_stats.user.balance = new_balance,
+                    Err(e) => {
+                        log::error!("Error charging a recovered cycle: {:#?}", e);
+                        dryer.charge_failed(charge);
+                        cycle_stats.charged -= charge;
+                        alert_admins(
+                            telegram_sender,
+                            admin_chat_ids,
+                            &format!(
+                                "{}Erro no banco de dados ao encerrar o ciclo de {} depois de um reinício, R${} não foram cobrados.",
+                                prefix, cycle_stats.user.name, charge
+                            ),
+                        );
+                    }
+                }
+            }
+            if reason == EndReason::RestartEnergyUnknown {
+                alert_admins(
+                    telegram_sender,
+                    admin_chat_ids,
+                    &format!(
+                        "{}Medidor indisponível ao encerrar o ciclo de {} depois de um reinício, o consumo final não foi cobrado.",
+                        prefix, cycle_stats.user.name
+                    ),
+                );
+            }
+            OutgoingMessage {
+                update_message_with_id: None,
+                chat_id: cycle_stats.user.chat_id,
+                text: format!(
+                    "{prefix}O sistema da secadora foi reiniciado e o seu ciclo foi encerrado. Custo: R${cost} referentes a {kwh:.2} kwh consumidos. Saldo remanescente de {balance}.{note}",
+                    prefix=prefix,
+                    cost=cycle_stats.charged,
+                    kwh=cycle_stats.total_consumed_kwh(),
+                    balance=cycle_stats.user.balance,
+                    note=if reason == EndReason::RestartEnergyUnknown {
+                        " O consumo enquanto o sistema estava fora do ar não pôde ser medido e não foi cobrado."
+                    } else {
+                        ""
+                    },
+                ),
+                buttons: Buttons::Dryer,
+            }
+        }
+    };
+    if let Err(e) = telegram_sender.try_send(text) {
+        log::error!("{:#?}", e);
+    }
+    persist_cycle_update(database, dryer).await;
+}
+
+/// Runs a tick of one machine, billing what it consumed and telling whoever is concerned about
+/// how the cycle ended or what failed
+async fn tick(
+    database: &database::Database,
+    telegram_sender: &std::sync::mpsc::SyncSender<OutgoingMessage>,
+    admin_chat_ids: &[i64],
+    dryer: &mut DryerManager,
+    prefix: &str,
+) {
+    let tick_outcome = dryer.tick().await;
+    if let Some(fault) = dryer.check_hardware() {
+        log::error!("Hardware fault on machine {}: {:?}", dryer.number(), fault);
+        let details = format!("{}{}", prefix, fault);
+        alert_admins(
+            telegram_sender,
+            admin_chat_ids,
+            &format!("Falha de hardware: {}", details),
+        );
+        if let Err(e) = database.record_hardware_fault(fault.kind(), &details).await {
+            log::error!("{:#?}", e);
+        }
+    }
+    match tick_outcome {
+        TickOutcome::TurnOffAndRemoveUserOutOfMoney(cycle_stats) => {
+            let response = OutgoingMessage {
+                update_message_with_id: None,
+                chat_id: cycle_stats.user.chat_id,
+                text: format!(
+                    "{prefix}Ciclo terminado por falta de saldo depois de {cycle_time}. Custo: R${cost} referentes a {kwh:.2} kwh consumidos. Saldo remanescente de {balance}.{warning}",
+                    prefix=prefix,
+                    cycle_time=seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
+                    cost=cycle_stats.charged,
+                    kwh=cycle_stats.total_consumed_kwh(),
+                    balance=cycle_stats.user.balance,
+                    warning=relay_warning(dryer),
+                ),
+                buttons: Buttons::Dryer,
+            };
+            if let Err(e) = telegram_sender.try_send(response) {
+                log::error!("{:#?}", e);
+            }
+        }
+        TickOutcome::TurnedOffDueToIdleTooLong(cycle_stats) => {
+            let response = OutgoingMessage {
+                update_message_with_id: None,
+                chat_id: cycle_stats.user.chat_id,
+                text: format!(
+                    "{prefix}Ciclo terminado depois de {cycle_time}. Custo: R${cost} referentes a {kwh:.2} kwh consumidos. Saldo remanescente de {balance}.{warning}",
+                    prefix=prefix,
+                    cycle_time=seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
+                    cost=cycle_stats.charged,
+                    kwh=cycle_stats.total_consumed_kwh(),
+                    balance=cycle_stats.user.balance,
+                    warning=relay_warning(dryer),
+                ),
+                buttons: Buttons::Dryer,
+            };
+            if let Err(e) = telegram_sender.try_send(response) {
+                log::error!("{:#?}", e);
+            }
+        }
+        TickOutcome::TurnedOffDueToMeterFailure { cycle_stats, error } => {
+            let response = OutgoingMessage {
+                update_message_with_id: None,
+                chat_id: cycle_stats.user.chat_id,
+                text: format!(
+                    "{prefix}Ciclo interrompido depois de {cycle_time} por falha no medidor de energia. Custo: R${cost} referentes a {kwh:.2} kwh consumidos. Saldo remanescente de {balance}.{warning}",
+                    prefix=prefix,
+                    cycle_time=seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
+                    cost=cycle_stats.charged,
+                    kwh=cycle_stats.total_consumed_kwh(),
+                    balance=cycle_stats.user.balance,
+                    warning=relay_warning(dryer),
+                ),
+                buttons: Buttons::Dryer,
+            };
+            if let Err(e) = telegram_sender.try_send(response) {
+                log::error!("{:#?}", e);
+            }
+            alert_admins(
+                telegram_sender,
+                admin_chat_ids,
+                &format!(
+                    "{}Secadora desligada por falha no medidor durante o ciclo de {}: {}",
+                    prefix, cycle_stats.user.name, error
+                ),
+            );
+        }
+        TickOutcome::DiscountConsumed {
+            charge,
+            cycle_stats,
+        } => {
+            match database
+                .charge_cycle(
+                    &cycle_stats,
+                    charge,
+                    &cycle_charge_description(&cycle_stats),
+                )
+                .await
+            {
+                Ok(new_balance) => {
+                    dryer.set_user_balance(new_balance);
+                }
+                Err(e) => {
+                    log::error!("DB error updating user balance: {:#?}", e);
+                    dryer.charge_failed(charge);
+                    if let Some(cycle_stats) = dryer.emergency_turn_off().await {
+                        let response = OutgoingMessage {
+                            update_message_with_id: None,
+                            chat_id: cycle_stats.user.chat_id,
+                            text: format!(
+                                "{prefix}Ciclo interrompido depois de {cycle_time} por um erro interno. Custo: R${cost} referentes a {kwh:.2} kwh consumidos.{warning}",
+                                prefix=prefix,
+                                cycle_time=seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
+                                cost=cycle_stats.charged,
+                                kwh=cycle_stats.total_consumed_kwh(),
+                                warning=relay_warning(dryer),
+                            ),
+                            buttons: Buttons::Dryer,
+                        };
+                        if let Err(e) = telegram_sender.try_send(response) {
+                            log::error!("{:#?}", e);
+                        }
+                        alert_admins(
+                            telegram_sender,
+                            admin_chat_ids,
+                            &format!(
+                                "{}Secadora desligada por erro no banco de dados durante o ciclo de {}, R${} não foram cobrados: {}",
+                                prefix, cycle_stats.user.name, charge, e
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        TickOutcome::Off
+        | TickOutcome::NotEnoughConsumptionToDiscountYet
+        | TickOutcome::MeterUnavailable => {}
+    }
+    persist_cycle_update(database, dryer).await;
+}
+
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
@@ -43,36 +407,95 @@ async fn main() {
         .unwrap();
     log_panics::init();
     let token = std::env::var("API_TOKEN").expect("No API TOKEN");
+    let admin_chat_ids = admin_chat_ids();
+    let admin_user_ids = admin_user_ids();
     let telegram_recv = telegram::Receiver::new(token.clone());
-    let telegram_sender = telegram::Sender::new(token).start_sender_background_thread();
     let database = database::Database::new().await;
-    let mut dryer = dryer_manager::DryerManager::new();
+    let mut dryers = dryers::Dryers::new();
+    admin::load_price(&database, &mut dryers).await;
+    let telegram_sender = telegram::Sender::new(token, dryers.count());
+    register_commands(&database, &telegram_sender, &admin_user_ids).await;
+    let telegram_sender = telegram_sender.start_sender_background_thread();
+    match database.load_active_cycles().await {
+        Ok(saved_cycles) => {
+            for saved in saved_cycles {
+                let number = u8::try_from(saved.machine).ok();
+                let prefix = number
+                    .map(|number| dryers.prefix(number))
+                    .unwrap_or_default();
+                match number.and_then(|number| dryers.get_mut(number)) {
+                    Some(dryer) => {
+                        recover_cycle(
+                            &database,
+                            &telegram_sender,
+                            &admin_chat_ids,
+                            dryer,
+                            &prefix,
+                            saved,
+                        )
+                        .await
+                    }
+                    None => {
+                        log::error!(
+                            "Cycle of {} on machine {} not recovered, the machine is gone",
+                            saved.name,
+                            saved.machine
+                        );
+                        alert_admins(
+                            &telegram_sender,
+                            &admin_chat_ids,
+                            &format!(
+                                "O ciclo de {} na secadora {} não foi retomado depois de um reinício, essa secadora não está mais configurada.",
+                                saved.name, saved.machine
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        Err(e) => log::error!("Error loading the active cycles: {:#?}", e),
+    }
     let update_recv = telegram_recv.start_listening_for_updates_in_background_thread();
 
     loop {
         match update_recv.recv_timeout(Duration::from_secs_f32(0.5)) {
             Ok(user_message) => {
                 let mut response = match database.get_db_id(user_message.user_id).await {
-                    Ok(maybe_user) => match maybe_user {
-                        None => {
-                            OutgoingMessage {
-                                update_message_with_id: None,
-                                chat_id: user_message.chat_id,
-                                text: format!("Você não está registrado, mande essa mensagem para o @TiberioFerreira com o seu id: {}", user_message.user_id),
-                                send_buttons: true,
-                            }
+                    Ok(user) => {
+                        let is_admin = admin_user_ids.contains(&user_message.user_id)
+                            || user.as_ref().is_some_and(|user| user.is_admin);
+                        let reply = |text: String, buttons| OutgoingMessage {
+                            update_message_with_id: None,
+                            chat_id: user_message.chat_id,
+                            text,
+                            buttons,
+                        };
+                        match (user_message.update.clone(), user) {
+                            // admins answering don't need to be users
+                            (MsgType::ApproveRegistration { telegram_id }, _) => registration::answer(&database, &telegram_sender, is_admin, &user_message, telegram_id, true).await,
+                            (MsgType::RejectRegistration { telegram_id }, _) => registration::answer(&database, &telegram_sender, is_admin, &user_message, telegram_id, false).await,
+                            (MsgType::Admin(command), _) if is_admin => admin::handle(&database, &mut dryers, &telegram_sender, &user_message, command).await,
+                            (MsgType::Admin(_), _) => reply("Comando exclusivo para administradores.".to_string(), Buttons::None),
+                            (MsgType::InvalidCommand { usage }, _) => reply(usage.to_string(), Buttons::None),
+                            (MsgType::UnknownCommand { command }, _) => reply(format!("Comando /{} desconhecido, veja /ajuda.", command), Buttons::None),
+                            (MsgType::Help, user) => reply(telegram::commands::help(is_admin), if user.is_some() { Buttons::Dryer } else { Buttons::None }),
+                            (MsgType::Register { name }, None) => registration::request(&database, &telegram_sender, &admin_chat_ids, &user_message, name).await,
+                            (_, None) => reply("Você não está registrado, mande /registrar SEU NOME para pedir acesso.".to_string(), Buttons::None),
+                            (MsgType::Register { .. }, Some(user)) => reply(format!("Você já está registrado como {}.", user.name), Buttons::Dryer),
+                            (_, Some(user)) if user.blocked => reply("Seu acesso à secadora está bloqueado, fale com um administrador.".to_string(), Buttons::None),
+                            (MsgType::History { cycles }, Some(_)) => reply(history::report(&database, user_message.user_id, cycles).await, Buttons::Dryer),
+                            (MsgType::Statement { entries }, Some(_)) => reply(history::statement(&database, user_message.user_id, entries).await, Buttons::Dryer),
+                            (_, Some(user)) => dryers.handle_telegram_msg(user_message.clone(), user).await,
                         }
-                        Some(user) => {
-                            dryer.handle_telegram_msg(user_message.clone(), user)
-                        },
-                    },
+                    }
                     Err(e) => {
                         log::error!("{:#?}", e);
                         OutgoingMessage {
                             update_message_with_id: None,
                             chat_id: user_message.chat_id,
-                            text: "Problema na rede interna da casa, fale com @TiberioFerreira".to_string(),
-                            send_buttons: true,
+                            text: "Problema na rede interna da casa, fale com @TiberioFerreira"
+                                .to_string(),
+                            buttons: Buttons::Dryer,
                         }
                     }
                 };
@@ -105,61 +528,12 @@ async fn main() {
                 }
             }
         }
-        let tick_outcome = dryer.tick();
-        match tick_outcome {
-            TickOutcome::TurnOffAndRemoveUserOutOfMoney(cycle_stats) => {
-                let response = OutgoingMessage {
-                    update_message_with_id: None,
-                    chat_id: cycle_stats.user.chat_id,
-                    text: format!(
-                        "Ciclo terminado por falta de saldo depois de {cycle_time}. Custo: R${cost_reais:.2} referentes a {kwh:.2} kwh consumidos. Saldo remanescente de {balance:.2}.",
-                        cycle_time=seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
-                        cost_reais=cycle_stats.total_consumed_and_discounted_reais,
-                        kwh=cycle_stats.total_consumed_kwh(),
-                        balance=cycle_stats.user.balance_reais,
-                    ),
-                    send_buttons: true,
-                };
-                if let Err(e) = telegram_sender.try_send(response) {
-                    log::error!("{:#?}", e);
-                }
-            }
-            TickOutcome::TurnedOffDueToIdleTooLong(cycle_stats) => {
-                let response = OutgoingMessage {
-                    update_message_with_id: None,
-                    chat_id: cycle_stats.user.chat_id,
-                    text: format!(
-                        "Ciclo terminado depois de {cycle_time}. Custo: R${cost_reais:.2} referentes a {kwh:.2} kwh consumidos. Saldo remanescente de {balance:.2}.",
-                        cycle_time=seconds_to_hour_format(cycle_stats.start_time.elapsed().as_secs()),
-                        cost_reais=cycle_stats.total_consumed_and_discounted_reais,
-                        kwh=cycle_stats.total_consumed_kwh(),
-                        balance=cycle_stats.user.balance_reais,
-                    ),
-                    send_buttons: true,
-                };
-                if let Err(e) = telegram_sender.try_send(response) {
-                    log::error!("{:#?}", e);
-                }
-            }
-            TickOutcome::DiscountConsumed {
-                delta_energy_kwh: _delta_energy_kwh,
-                delta_consumed_reais,
-                user,
-            } => {
-                match database
-                    .update_user_balance(user.telegram_id, delta_consumed_reais)
-                    .await
-                {
-                    Ok(new_balance) => {
-                        dryer.set_user_balance_reais(new_balance);
-                    }
-                    Err(e) => {
-                        dryer.emergency_turn_off();
-                        panic!("DB error updating user balance!{:#?}", e);
-                    }
-                }
-            }
-            TickOutcome::Off | TickOutcome::NotEnoughConsumptionToDiscountYet => {}
+        for number in 1..=dryers.count() {
+            let prefix = dryers.prefix(number);
+            let dryer = dryers
+                .get_mut(number)
+                .expect("Machines are numbered from 1");
+            tick(&database, &telegram_sender, &admin_chat_ids, dryer, &prefix).await;
         }
     }
 }
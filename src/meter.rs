@@ -0,0 +1,121 @@
+use crate::error::Error;
+
+mod async_meter;
+mod sampler;
+
+pub use async_meter::AsyncMeter;
+pub use sampler::{PowerStats, Sample, Sampler};
+
+/// PZEM energy counters roll over to zero after 9999.99kWh
+pub const ENERGY_COUNTER_ROLLOVER_WH: u32 = 10_000_000;
+/// How close to the rollover the counter must have been for a decrease to count as a rollover
+/// instead of a reset, way more than a dryer can use between two readings
+const ROLLOVER_MARGIN_WH: u32 = 100_000;
+
+/// One reading of everything the meter measures
+#[derive(Debug, Clone)]
+pub struct Data {
+    pub voltage: f32,
+    pub current: f32,
+    pub power_w: f32,
+    pub energy_wh: u32,
+    pub frequency: f32,
+    pub power_factor: f32,
+    pub alarm: f32,
+}
+
+/// Energy consumed between two readings of the meter energy counter.
+///
+/// A counter going down either rolled over, when it was close to the limit, or was reset
+/// (by the button on v1 meters or a reset command), in which case everything it counted
+/// since the reset is new consumption.
+pub fn energy_delta_wh(previous_wh: u32, current_wh: u32) -> u32 {
+    if current_wh >= previous_wh {
+        current_wh - previous_wh
+    } else if previous_wh >= ENERGY_COUNTER_ROLLOVER_WH - ROLLOVER_MARGIN_WH {
+        ENERGY_COUNTER_ROLLOVER_WH - previous_wh + current_wh
+    } else {
+        log::warn!(
+            "Energy counter went from {}Wh to {}Wh, assuming it was reset",
+            previous_wh,
+            current_wh
+        );
+        current_wh
+    }
+}
+
+/// Energy meter the dryer is wired through, implemented by both PZEM generations.
+///
+/// Only [`EnergyMeter::read_data`] and [`EnergyMeter::reset_energy`] are required, meters
+/// able to read a single quantity cheaper than a full reading can override the others.
+pub trait EnergyMeter {
+    fn read_data(&mut self) -> Result<Data, Error>;
+
+    /// Zeroes the accumulated energy counter
+    fn reset_energy(&mut self) -> Result<(), Error>;
+
+    fn voltage(&mut self) -> Result<f32, Error> {
+        Ok(self.read_data()?.voltage)
+    }
+
+    fn current(&mut self) -> Result<f32, Error> {
+        Ok(self.read_data()?.current)
+    }
+
+    fn power_w(&mut self) -> Result<f32, Error> {
+        Ok(self.read_data()?.power_w)
+    }
+
+    fn energy_wh(&mut self) -> Result<u32, Error> {
+        Ok(self.read_data()?.energy_wh)
+    }
+
+    fn frequency(&mut self) -> Result<f32, Error> {
+        Ok(self.read_data()?.frequency)
+    }
+
+    fn power_factor(&mut self) -> Result<f32, Error> {
+        Ok(self.read_data()?.power_factor)
+    }
+}
+
+impl<M: EnergyMeter + ?Sized> EnergyMeter for Box<M> {
+    fn read_data(&mut self) -> Result<Data, Error> {
+        (**self).read_data()
+    }
+
+    fn reset_energy(&mut self) -> Result<(), Error> {
+        (**self).reset_energy()
+    }
+
+    fn voltage(&mut self) -> Result<f32, Error> {
+        (**self).voltage()
+    }
+
+    fn current(&mut self) -> Result<f32, Error> {
+        (**self).current()
+    }
+
+    fn power_w(&mut self) -> Result<f32, Error> {
+        (**self).power_w()
+    }
+
+    fn energy_wh(&mut self) -> Result<u32, Error> {
+        (**self).energy_wh()
+    }
+
+    fn frequency(&mut self) -> Result<f32, Error> {
+        (**self).frequency()
+    }
+
+    fn power_factor(&mut self) -> Result<f32, Error> {
+        (**self).power_factor()
+    }
+}
+
+#[test]
+fn energy_delta_handles_rollover_and_reset() {
+    assert_eq!(energy_delta_wh(1_000, 1_250), 250);
+    assert_eq!(energy_delta_wh(9_999_990, 15), 25);
+    assert_eq!(energy_delta_wh(5_000, 3), 3);
+}
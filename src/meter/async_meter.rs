@@ -0,0 +1,62 @@
+use super::{Data, EnergyMeter};
+use crate::error::Error;
+use crate::io_thread::IoThread;
+
+/// Async handle to a meter living in its own I/O thread, see [`IoThread`]. Handles are cheap to
+/// clone and all share the same meter.
+#[derive(Debug, Clone)]
+pub struct AsyncMeter {
+    io: IoThread<Box<dyn EnergyMeter + Send>>,
+}
+
+impl AsyncMeter {
+    pub fn spawn<M: EnergyMeter + Send + 'static>(meter: M) -> Self {
+        Self {
+            io: IoThread::spawn("Meter", Box::new(meter)),
+        }
+    }
+
+    pub async fn read_data(&self) -> Result<Data, Error> {
+        self.io
+            .run(|meter| meter.read_data())
+            .await
+            .unwrap_or_else(|| Err(io_thread_gone()))
+    }
+
+    pub async fn reset_energy(&self) -> Result<(), Error> {
+        self.io
+            .run(|meter| meter.reset_energy())
+            .await
+            .unwrap_or_else(|| Err(io_thread_gone()))
+    }
+
+    pub async fn power_w(&self) -> Result<f32, Error> {
+        Ok(self.read_data().await?.power_w)
+    }
+
+    pub async fn energy_wh(&self) -> Result<u32, Error> {
+        Ok(self.read_data().await?.energy_wh)
+    }
+}
+
+fn io_thread_gone() -> Error {
+    Error::Transport(std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "Meter I/O thread is gone",
+    ))
+}
+
+#[tokio::test]
+async fn async_meter_talks_to_the_meter_from_its_own_thread() {
+    use crate::emulator::Emulator;
+    use crate::pzemv3::Pzem;
+    let emulator = Emulator::with_manual_clock();
+    emulator.set_power_w(1800.);
+    emulator.set_energy_wh(500);
+    let meter = AsyncMeter::spawn(Pzem::new(emulator.port()));
+    let other_handle = meter.clone();
+    assert_eq!(meter.power_w().await.unwrap(), 1800.);
+    assert_eq!(other_handle.energy_wh().await.unwrap(), 500);
+    meter.reset_energy().await.unwrap();
+    assert_eq!(emulator.energy_wh(), 0);
+}
@@ -0,0 +1,182 @@
+use super::{AsyncMeter, Data};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// One meter reading and when it was taken
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub taken_at: Instant,
+    pub data: Data,
+}
+
+/// Power statistics over a window of samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerStats {
+    pub average_w: f32,
+    pub min_w: f32,
+    pub max_w: f32,
+    pub samples: usize,
+}
+
+#[derive(Debug)]
+struct Samples {
+    readings: VecDeque<Sample>,
+    capacity: usize,
+    consecutive_failures: u32,
+}
+
+/// Polls a meter at a fixed rate, keeping the last readings in a bounded ring buffer.
+///
+/// Everyone interested in what the meter measures reads from here instead of the bus, so the
+/// amount of traffic on the line doesn't depend on how many people ask for the dryer status.
+/// Polling stops when the sampler is dropped.
+#[derive(Debug)]
+pub struct Sampler {
+    meter: AsyncMeter,
+    samples: Arc<Mutex<Samples>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Sampler {
+    /// Must be called from within a tokio runtime
+    pub fn spawn(meter: AsyncMeter, period: Duration, capacity: usize) -> Self {
+        assert!(capacity > 0, "Sampler needs room for at least one sample");
+        let samples = Arc::new(Mutex::new(Samples {
+            readings: VecDeque::with_capacity(capacity),
+            capacity,
+            consecutive_failures: 0,
+        }));
+        let task = tokio::spawn(Self::poll(meter.clone(), period, samples.clone()));
+        Self {
+            meter,
+            samples,
+            task,
+        }
+    }
+
+    async fn poll(meter: AsyncMeter, period: Duration, samples: Arc<Mutex<Samples>>) {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let reading = meter.read_data().await;
+            let mut samples = samples.lock().expect("Sampler lock poisoned");
+            match reading {
+                Ok(data) => {
+                    if samples.readings.len() == samples.capacity {
+                        samples.readings.pop_front();
+                    }
+                    samples.readings.push_back(Sample {
+                        taken_at: Instant::now(),
+                        data,
+                    });
+                    samples.consecutive_failures = 0;
+                }
+                Err(e) => {
+                    samples.consecutive_failures += 1;
+                    log::warn!(
+                        "Error sampling meter ({} in a row): {}",
+                        samples.consecutive_failures,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    fn samples(&self) -> MutexGuard<'_, Samples> {
+        self.samples.lock().expect("Sampler lock poisoned")
+    }
+
+    /// The meter itself, for the rare reading that can't wait for the next sample
+    pub fn meter(&self) -> &AsyncMeter {
+        &self.meter
+    }
+
+    pub fn latest(&self) -> Option<Sample> {
+        self.samples().readings.back().cloned()
+    }
+
+    /// Every sample still in the buffer taken after `since`, oldest first
+    pub fn samples_since(&self, since: Instant) -> Vec<Sample> {
+        self.samples()
+            .readings
+            .iter()
+            .filter(|s| s.taken_at > since)
+            .cloned()
+            .collect()
+    }
+
+    /// How many polls failed since the last successful one
+    pub fn consecutive_failures(&self) -> u32 {
+        self.samples().consecutive_failures
+    }
+
+    /// Power statistics over the samples taken in the last `window`, `None` if there are none
+    pub fn power_stats(&self, window: Duration) -> Option<PowerStats> {
+        let samples = self.samples();
+        let now = Instant::now();
+        let powers: Vec<f32> = samples
+            .readings
+            .iter()
+            .filter(|s| now.duration_since(s.taken_at) <= window)
+            .map(|s| s.data.power_w)
+            .collect();
+        if powers.is_empty() {
+            return None;
+        }
+        Some(PowerStats {
+            average_w: powers.iter().sum::<f32>() / powers.len() as f32,
+            min_w: powers.iter().copied().fold(f32::INFINITY, f32::min),
+            max_w: powers.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            samples: powers.len(),
+        })
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn sampler_keeps_a_bounded_window_of_readings() {
+    use crate::emulator::{Emulator, Fault};
+    use crate::pzemv3::Pzem;
+    const PERIOD: Duration = Duration::from_millis(5);
+    let emulator = Emulator::with_manual_clock();
+    emulator.set_power_curve(|_| 1000.);
+    let sampler = Sampler::spawn(AsyncMeter::spawn(Pzem::new(emulator.port())), PERIOD, 3);
+    // time is paused, sleeping only lets the sampler poll the meter
+    while sampler.samples().readings.len() < 3 {
+        tokio::time::sleep(PERIOD).await;
+    }
+    let oldest = sampler.samples().readings[0].taken_at;
+    while sampler.samples().readings[0].taken_at == oldest {
+        tokio::time::sleep(PERIOD).await;
+    }
+    assert_eq!(sampler.samples().readings.len(), 3);
+    emulator.set_power_w(2000.);
+    while sampler
+        .samples()
+        .readings
+        .iter()
+        .any(|s| s.data.power_w != 2000.)
+    {
+        tokio::time::sleep(PERIOD).await;
+    }
+    assert_eq!(sampler.latest().unwrap().data.power_w, 2000.);
+    let stats = sampler.power_stats(Duration::from_secs(60)).unwrap();
+    assert_eq!(stats.samples, 3);
+    assert_eq!(stats.max_w, 2000.);
+
+    for _ in 0..1000 {
+        emulator.inject(Fault::NoReply);
+    }
+    while sampler.consecutive_failures() == 0 {
+        tokio::time::sleep(PERIOD).await;
+    }
+    assert_eq!(sampler.latest().unwrap().data.power_w, 2000.);
+}
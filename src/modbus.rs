@@ -0,0 +1,397 @@
+use crate::error::Error;
+use crate::transport::Transport;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const READ_INPUT_REGISTERS: u8 = 0x04;
+pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+/// Set on the function code of a reply when the slave answers with an exception
+pub const EXCEPTION_FLAG: u8 = 0x80;
+/// PZEM-004T v3 only talks at 9600 baud
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+
+/// Exception codes as documented in the PZEM-004T v3 datasheet (they match standard Modbus)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionCode {
+    IllegalFunction,
+    IllegalAddress,
+    IllegalData,
+    SlaveError,
+    Unknown(u8),
+}
+
+impl From<u8> for ExceptionCode {
+    fn from(code: u8) -> Self {
+        match code {
+            0x01 => ExceptionCode::IllegalFunction,
+            0x02 => ExceptionCode::IllegalAddress,
+            0x03 => ExceptionCode::IllegalData,
+            0x04 => ExceptionCode::SlaveError,
+            other => ExceptionCode::Unknown(other),
+        }
+    }
+}
+
+impl From<ExceptionCode> for u8 {
+    fn from(code: ExceptionCode) -> Self {
+        match code {
+            ExceptionCode::IllegalFunction => 0x01,
+            ExceptionCode::IllegalAddress => 0x02,
+            ExceptionCode::IllegalData => 0x03,
+            ExceptionCode::SlaveError => 0x04,
+            ExceptionCode::Unknown(other) => other,
+        }
+    }
+}
+
+impl Display for ExceptionCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExceptionCode::IllegalFunction => write!(f, "illegal function (0x01)"),
+            ExceptionCode::IllegalAddress => write!(f, "illegal address (0x02)"),
+            ExceptionCode::IllegalData => write!(f, "illegal data (0x03)"),
+            ExceptionCode::SlaveError => write!(f, "slave error (0x04)"),
+            ExceptionCode::Unknown(code) => write!(f, "unknown exception code ({:#04x})", code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    slave: u8,
+    function: u8,
+    data: Vec<u8>,
+}
+
+impl Request {
+    pub fn read_input_registers(slave: u8, start: u16, count: u16) -> Self {
+        Self::with_two_words(slave, READ_INPUT_REGISTERS, start, count)
+    }
+
+    pub fn read_holding_registers(slave: u8, start: u16, count: u16) -> Self {
+        Self::with_two_words(slave, READ_HOLDING_REGISTERS, start, count)
+    }
+
+    pub fn write_single_register(slave: u8, register: u16, value: u16) -> Self {
+        Self::with_two_words(slave, WRITE_SINGLE_REGISTER, register, value)
+    }
+
+    /// Vendor specific function, like the PZEM 0x42 energy reset.
+    /// The slave is expected to echo the request back on success.
+    pub fn custom(slave: u8, function: u8, data: Vec<u8>) -> Self {
+        Self {
+            slave,
+            function,
+            data,
+        }
+    }
+
+    fn with_two_words(slave: u8, function: u8, first: u16, second: u16) -> Self {
+        let mut data = Vec::with_capacity(4);
+        data.extend_from_slice(&first.to_be_bytes());
+        data.extend_from_slice(&second.to_be_bytes());
+        Self {
+            slave,
+            function,
+            data,
+        }
+    }
+
+    pub fn slave(&self) -> u8 {
+        self.slave
+    }
+
+    pub fn function(&self) -> u8 {
+        self.function
+    }
+
+    /// [Slave Address, Function, Data..., CRC Low Byte, CRC High Byte]
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(self.data.len() + 4);
+        frame.push(self.slave);
+        frame.push(self.function);
+        frame.extend_from_slice(&self.data);
+        frame.extend_from_slice(&[0, 0]);
+        crc_write(&mut frame);
+        frame
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Registers(Vec<u16>),
+    WriteSingleRegister {
+        register: u16,
+        value: u16,
+    },
+    /// Echo of a vendor specific request
+    Echo(Vec<u8>),
+}
+
+/// Validates a full reply frame (including CRC) to `request` and decodes it.
+pub fn parse_response(request: &Request, frame: &[u8]) -> Result<Response, Error> {
+    if frame.len() < 4 {
+        return Err(Error::UnexpectedLength {
+            expected: 4,
+            actual: frame.len(),
+        });
+    }
+    if !crc_is_valid(frame) {
+        return Err(Error::Crc);
+    }
+    if frame[0] != request.slave {
+        return Err(Error::UnexpectedReply(format!(
+            "Reply from slave {:#04x} while talking to {:#04x}",
+            frame[0], request.slave
+        )));
+    }
+    let function = frame[1];
+    if function == request.function | EXCEPTION_FLAG {
+        return Err(Error::Exception(ExceptionCode::from(frame[2])));
+    }
+    if function != request.function {
+        return Err(Error::UnexpectedReply(format!(
+            "Reply to function {:#04x} while {:#04x} was requested",
+            function, request.function
+        )));
+    }
+    let payload = &frame[2..frame.len() - 2];
+    match function {
+        READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            let byte_count = payload.first().copied().unwrap_or(0) as usize;
+            let requested = match request.data.get(2..4) {
+                Some(count) => u16::from_be_bytes([count[0], count[1]]) as usize,
+                None => byte_count / 2,
+            };
+            if byte_count != requested * 2 || payload.len() != byte_count + 1 {
+                return Err(Error::UnexpectedLength {
+                    expected: requested * 2,
+                    actual: byte_count,
+                });
+            }
+            let registers = payload[1..]
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            Ok(Response::Registers(registers))
+        }
+        WRITE_SINGLE_REGISTER => {
+            if payload.len() != 4 {
+                return Err(Error::UnexpectedLength {
+                    expected: 4,
+                    actual: payload.len(),
+                });
+            }
+            Ok(Response::WriteSingleRegister {
+                register: u16::from_be_bytes([payload[0], payload[1]]),
+                value: u16::from_be_bytes([payload[2], payload[3]]),
+            })
+        }
+        _ => Ok(Response::Echo(payload.to_vec())),
+    }
+}
+
+/// Anything able to carry out a Modbus request/reply exchange, be it a master owning the
+/// transport or a handle to one shared with other devices on the same bus
+pub trait Master {
+    fn transact(&mut self, request: &Request) -> Result<Response, Error>;
+    /// Waits until the line has been quiet for a whole silent interval, throwing away anything
+    /// received meanwhile, so the next request is seen as the start of a new frame.
+    fn resynchronize(&mut self) -> Result<(), Error>;
+}
+
+/// Modbus RTU frames are delimited by at least 3.5 characters of silence on the line, fixed
+/// at 1.75ms above 19200 baud. A character is 11 bits (start, 8 data, parity/stop, stop).
+pub fn silent_interval(baud_rate: u32) -> Duration {
+    if baud_rate > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        Duration::from_secs_f64(3.5 * 11. / f64::from(baud_rate))
+    }
+}
+
+/// Modbus RTU master: sends one request at a time and reads back the reply
+#[derive(Debug)]
+pub struct RtuMaster<T: Transport> {
+    transport: T,
+    silent_interval: Duration,
+    last_frame_at: Option<Instant>,
+}
+
+impl<T: Transport> RtuMaster<T> {
+    pub fn new(transport: T) -> Self {
+        Self::with_baud_rate(transport, DEFAULT_BAUD_RATE)
+    }
+
+    pub fn with_baud_rate(transport: T, baud_rate: u32) -> Self {
+        Self {
+            transport,
+            silent_interval: silent_interval(baud_rate),
+            last_frame_at: None,
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Makes sure the previous frame is followed by a silent interval before a new one starts
+    fn wait_silent_interval(&self) {
+        if let Some(last_frame_at) = self.last_frame_at {
+            let elapsed = last_frame_at.elapsed();
+            if elapsed < self.silent_interval {
+                std::thread::sleep(self.silent_interval - elapsed);
+            }
+        }
+    }
+
+    /// Reads exactly one reply frame, using the function code and byte count to know its size
+    fn read_frame(&mut self, request: &Request) -> Result<Vec<u8>, Error> {
+        // [Slave Address, Function, Byte Count | Exception Code | First Data Byte]
+        let mut frame = vec![0; 3];
+        self.transport.read_exact(&mut frame)?;
+        let function = frame[1];
+        let total_len = if function & EXCEPTION_FLAG != 0 {
+            5
+        } else {
+            match function {
+                READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => 3 + frame[2] as usize + 2,
+                // everything else is an echo of the request
+                _ => request.to_frame().len(),
+            }
+        };
+        if total_len > frame.len() {
+            let start = frame.len();
+            frame.resize(total_len, 0);
+            self.transport.read_exact(&mut frame[start..])?;
+        }
+        Ok(frame)
+    }
+}
+
+impl<T: Transport> Master for RtuMaster<T> {
+    fn transact(&mut self, request: &Request) -> Result<Response, Error> {
+        self.wait_silent_interval();
+        let leftover = self.transport.discard_input()?;
+        if leftover != 0 {
+            log::warn!("Had {} bytes leftover in the line!", leftover);
+        }
+        self.transport.write_all(&request.to_frame())?;
+        self.transport.flush()?;
+        let frame = self.read_frame(request);
+        self.last_frame_at = Some(Instant::now());
+        parse_response(request, &frame?)
+    }
+
+    fn resynchronize(&mut self) -> Result<(), Error> {
+        // a misbehaving slave may still be talking, give up waiting for silence eventually
+        for _ in 0..100 {
+            std::thread::sleep(self.silent_interval);
+            let discarded = self.transport.discard_input()?;
+            if discarded == 0 {
+                self.last_frame_at = Some(Instant::now());
+                return Ok(());
+            }
+            log::warn!("Discarded {} bytes while resynchronizing", discarded);
+        }
+        Err(Error::UnexpectedReply(
+            "Line never went quiet while resynchronizing".to_string(),
+        ))
+    }
+}
+
+/// 16-bit cyclic redundancy check (CRC), written in the last 2 bytes of `buf`.
+pub fn crc_write(buf: &mut [u8]) {
+    let n = buf.len();
+    assert!(n > 3, "Need at least 3 bytes to calculate the CRC check");
+    // this results in the bytes possibly "inverted", we later make it big endian
+    let crc = crc::Crc::<u16>::new(&crc::CRC_16_MODBUS);
+    let mut digest = crc.digest();
+    digest.update(&buf[0..n - 2]);
+    let final_res = digest.finalize().to_be().to_be_bytes();
+    buf[n - 2] = final_res[0];
+    buf[n - 1] = final_res[1];
+}
+
+/// Returns true if the CRC in the last 2 bytes of `buf` is valid
+pub fn crc_is_valid(buf: &[u8]) -> bool {
+    let n = buf.len();
+    assert!(n > 3, "Need at least 3 bytes to calculate the CRC check");
+    let crc = crc::Crc::<u16>::new(&crc::CRC_16_MODBUS);
+    let mut digest = crc.digest();
+    digest.update(&buf[0..n - 2]);
+    let final_res = digest.finalize().to_be().to_be_bytes();
+    final_res == buf[n - 2..]
+}
+
+#[test]
+fn crc_work() {
+    let correct_message_with_crc = [
+        0x01, 0x4, 0x14, 0x4, 0xEB, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 2, 0x57, 0, 0, 0, 0, 0x18,
+        0xB8,
+    ];
+    assert!(crc_is_valid(&correct_message_with_crc));
+    let mut msg = [
+        0x01, 0x4, 0x14, 0x4, 0xEB, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 2, 0x57, 0, 0, 0, 0, 0, 0,
+    ];
+    crc_write(&mut msg);
+    let len = msg.len();
+    assert_eq!(&correct_message_with_crc[len - 2..], &msg[len - 2..]);
+}
+
+#[test]
+fn request_frames_match_datasheet() {
+    // Example from the PZEM-004T v3 datasheet: reset energy of slave 1
+    assert_eq!(
+        Request::custom(0x01, 0x42, vec![]).to_frame(),
+        vec![0x01, 0x42, 0x80, 0x11]
+    );
+    let frame = Request::read_input_registers(0x01, 0x0000, 0x000A).to_frame();
+    assert_eq!(&frame[..6], &[0x01, 0x04, 0x00, 0x00, 0x00, 0x0A]);
+    assert!(crc_is_valid(&frame));
+}
+
+#[test]
+fn parses_exceptions_and_rejects_bad_frames() {
+    use crate::transport::MemoryTransport;
+    let request = Request::read_input_registers(0x01, 0, 1);
+    let mut exception = vec![0x01, 0x84, 0x02, 0, 0];
+    crc_write(&mut exception);
+    let mut registers = vec![0x01, 0x04, 0x02, 0x08, 0x98, 0, 0];
+    crc_write(&mut registers);
+    let mut corrupted = registers.clone();
+    corrupted[4] ^= 0xFF;
+
+    let mut transport = MemoryTransport::new();
+    transport.push_reply(&exception);
+    transport.push_reply(&registers);
+    transport.push_reply(&corrupted);
+    transport.push_reply(&[]);
+    let mut master = RtuMaster::new(transport);
+
+    assert!(matches!(
+        master.transact(&request),
+        Err(Error::Exception(ExceptionCode::IllegalAddress))
+    ));
+    assert_eq!(
+        master.transact(&request).unwrap(),
+        Response::Registers(vec![2200])
+    );
+    assert!(matches!(master.transact(&request), Err(Error::Crc)));
+    assert!(matches!(master.transact(&request), Err(Error::Timeout)));
+}
+
+#[test]
+fn silent_interval_follows_baud_rate() {
+    assert_eq!(silent_interval(9600).as_micros(), 4010);
+    assert_eq!(silent_interval(115200), Duration::from_micros(1750));
+}
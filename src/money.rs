@@ -0,0 +1,174 @@
+use std::fmt::{Display, Formatter};
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+
+/// An amount of money, in integer centavos.
+///
+/// Stored in the database as `numeric(12, 2)` reais, the queries convert from and to centavos
+/// so no amount ever goes through a float.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub const fn from_centavos(centavos: i64) -> Self {
+        Self(centavos)
+    }
+
+    pub const fn centavos(self) -> i64 {
+        self.0
+    }
+}
+
+/// Reais with two decimals and no currency symbol, like `12.30` or `-0.05`, `{:+}` always
+/// shows the sign
+impl Display for Money {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 {
+            "-"
+        } else if f.sign_plus() {
+            "+"
+        } else {
+            ""
+        };
+        let centavos = self.0.unsigned_abs();
+        write!(f, "{}{}.{:02}", sign, centavos / 100, centavos % 100)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, rhs: Money) -> Money {
+        Money(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, rhs: Money) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, rhs: Money) -> Money {
+        Money(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, rhs: Money) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+/// Reais as people type them: `12`, `12,5`, `12.50` or `R$ 12,50`, at most two decimals
+impl std::str::FromStr for Money {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid amount `{}`, should be like 12,50", s);
+        let amount = s.trim();
+        let amount = amount.strip_prefix("R$").unwrap_or(amount).trim_start();
+        let (negative, amount) = match amount.strip_prefix('-') {
+            Some(amount) => (true, amount),
+            None => (false, amount),
+        };
+        let (reais, centavos) = match amount.split_once([',', '.']) {
+            Some((reais, centavos)) => (reais, centavos),
+            None => (amount, ""),
+        };
+        let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+        if reais.is_empty() || !digits(reais) || !digits(centavos) || centavos.len() > 2 {
+            return Err(invalid());
+        }
+        let reais: i64 = reais.parse().map_err(|_| invalid())?;
+        let centavos: i64 = format!("{:0<2}", centavos).parse().map_err(|_| invalid())?;
+        let total = reais
+            .checked_mul(100)
+            .and_then(|r| r.checked_add(centavos))
+            .ok_or_else(invalid)?;
+        Ok(Money(if negative { -total } else { total }))
+    }
+}
+
+/// Price of energy, in whole centavos per kWh
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnergyPrice {
+    centavos_per_kwh: i64,
+}
+
+impl EnergyPrice {
+    pub const fn from_centavos_per_kwh(centavos_per_kwh: i64) -> Self {
+        Self { centavos_per_kwh }
+    }
+
+    pub const fn per_kwh(self) -> Money {
+        Money(self.centavos_per_kwh)
+    }
+
+    /// Exact cost of `wh`, in thousandths of a centavo (1Wh at N centavos per kWh costs N of them)
+    pub fn cost_millicentavos(self, wh: u32) -> i64 {
+        i64::from(wh) * self.centavos_per_kwh
+    }
+}
+
+/// Splits an exact amount in thousandths of a centavo in what can be charged, rounded down to
+/// whole centavos, and the remainder, to be carried forward until it adds up to a centavo.
+/// Rounding down means nobody is ever charged for energy they didn't use yet.
+pub fn split_millicentavos(millicentavos: i64) -> (Money, i64) {
+    (
+        Money(millicentavos.div_euclid(1000)),
+        millicentavos.rem_euclid(1000),
+    )
+}
+
+#[test]
+fn money_displays_as_reais() {
+    assert_eq!(Money::from_centavos(1230).to_string(), "12.30");
+    assert_eq!(Money::from_centavos(-5).to_string(), "-0.05");
+    assert_eq!(format!("{:+}", Money::from_centavos(1000)), "+10.00");
+    assert_eq!(format!("{:+}", Money::from_centavos(-1)), "-0.01");
+    assert_eq!(
+        Money::from_centavos(100) - Money::from_centavos(250),
+        -Money::from_centavos(150)
+    );
+}
+
+#[test]
+fn money_is_parsed_from_reais() {
+    assert_eq!("12".parse(), Ok(Money::from_centavos(1200)));
+    assert_eq!("12,5".parse(), Ok(Money::from_centavos(1250)));
+    assert_eq!("R$ 0.05".parse(), Ok(Money::from_centavos(5)));
+    assert_eq!("-1,10".parse(), Ok(Money::from_centavos(-110)));
+    assert!("12,345".parse::<Money>().is_err());
+    assert!(",50".parse::<Money>().is_err());
+    assert!("doze".parse::<Money>().is_err());
+}
+
+#[test]
+fn sub_centavo_energy_is_carried_forward() {
+    let price = EnergyPrice::from_centavos_per_kwh(110);
+    // 1 centavo buys 9.09Wh, charging 1Wh at a time must add up exactly
+    let mut carried = 0;
+    let mut charged = Money::ZERO;
+    for _ in 0..1000 {
+        let (charge, rest) = split_millicentavos(carried + price.cost_millicentavos(1));
+        charged += charge;
+        carried = rest;
+    }
+    assert_eq!(charged, Money::from_centavos(110));
+    assert_eq!(carried, 0);
+    assert_eq!(split_millicentavos(2_999), (Money::from_centavos(2), 999));
+}
@@ -1,59 +1,244 @@
+use crate::error::Error;
+use crate::meter::{Data, EnergyMeter};
+use crate::transport::Transport;
 use log;
-use serialport::ClearBuffer;
-use std::io::{Read, Write};
+use std::fmt::Formatter;
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
 
-pub struct Pzem {
-    uart: Box<dyn serialport::SerialPort>,
+/// v1 meters are addressed by an "IP", this is the factory default
+pub const DEFAULT_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+/// Power alarm threshold range, in kW
+pub const POWER_ALARM_THRESHOLD_RANGE_KW: RangeInclusive<u8> = 1..=22;
+
+const READ_VOLTAGE: u8 = 0xB0;
+const READ_CURRENT: u8 = 0xB1;
+const READ_POWER: u8 = 0xB2;
+const READ_ENERGY: u8 = 0xB3;
+const SET_ADDRESS: u8 = 0xB4;
+const SET_POWER_ALARM: u8 = 0xB5;
+/// Replies use the request command minus this, 0xB0 -> 0xA0
+const REPLY_OFFSET: u8 = 0x10;
+
+pub struct Pzem<T: Transport = Box<dyn serialport::SerialPort>> {
+    uart: T,
+    address: Ipv4Addr,
+}
+
+impl<T: Transport> std::fmt::Debug for Pzem<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pzem")
+            .field("uart", &"dyn Transport")
+            .field("address", &self.address)
+            .finish()
+    }
 }
 
-impl Pzem {
-    pub fn new<T: Into<Box<dyn serialport::SerialPort>>>(serialport: T) -> Self {
+impl<T: Transport> Pzem<T> {
+    pub fn new(transport: T) -> Self {
+        Self::with_address(transport, DEFAULT_ADDRESS)
+    }
+
+    pub fn with_address(transport: T, address: Ipv4Addr) -> Self {
         Self {
-            uart: serialport.into(),
+            uart: transport,
+            address,
         }
     }
 
-    pub fn send_request_get_response(&mut self, request: [u8; 7]) -> std::io::Result<[u8; 7]> {
+    /// Address this driver is currently talking to
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    // Sum of all the previous bytes, truncated to 8 bits
+    fn checksum(packet: &[u8]) -> u8 {
+        packet[..packet.len() - 1]
+            .iter()
+            .fold(0_u8, |acc, curr| acc.wrapping_add(*curr))
+    }
+
+    /// [Command, Address 4 bytes, Data, Checksum]
+    fn request(&self, command: u8, data: u8) -> [u8; 7] {
+        let address = self.address.octets();
+        let mut request = [
+            command, address[0], address[1], address[2], address[3], data, 0,
+        ];
+        request[6] = Self::checksum(&request);
+        request
+    }
+
+    /// Sends a raw `[Command, Address 4 bytes, Data, Checksum]` request and checks the reply
+    /// is the one for its command
+    pub fn send_request_get_response(&mut self, request: [u8; 7]) -> Result<[u8; 7], Error> {
+        let expected_reply = request[0].checked_sub(REPLY_OFFSET).ok_or_else(|| {
+            Error::InvalidArgument(format!("No reply matches command {:#04x}", request[0]))
+        })?;
+        let leftover = self.uart.discard_input()?;
+        if leftover != 0 {
+            log::warn!(
+                "Found {} extra bytes in read buffer, cleared them!",
+                leftover
+            );
+        }
         self.uart.write_all(&request)?;
         self.uart.flush()?;
         let mut response = [0; 7];
         self.uart.read_exact(&mut response)?;
-        if self.uart.bytes_to_read()? != 0 {
-            log::warn!("Found extra bytes in read buffer, clearing them!");
-            self.uart.clear(ClearBuffer::Input)?;
-        }
-        let checksum_byte = response[6];
-        let sum = response[0..(response.len() - 2)]
-            .iter()
-            .fold(0 as u8, |acc, curr| acc.wrapping_add(*curr));
-        if checksum_byte != sum {
+        if response[6] != Self::checksum(&response) {
             log::error!("Checksum failed");
-            std::io::Error::new(std::io::ErrorKind::Other, "Pzem packet checksum failed");
+            return Err(Error::Crc);
+        }
+        if response[0] != expected_reply {
+            return Err(Error::UnexpectedReply(format!(
+                "Reply {:#04x} to command {:#04x}",
+                response[0], request[0]
+            )));
         }
         Ok(response)
     }
 
-    pub fn read_voltage(&mut self) -> std::io::Result<f32> {
-        let read_voltage_packet = [0xB0, 0xC0, 0xA8, 0x01, 0x01, 0x00, 0x1A];
-        let response = self.send_request_get_response(read_voltage_packet)?;
-        let integer_part = response[2];
+    fn command(&mut self, command: u8, data: u8) -> Result<[u8; 7], Error> {
+        let request = self.request(command, data);
+        self.send_request_get_response(request)
+    }
+
+    pub fn read_voltage(&mut self) -> Result<f32, Error> {
+        let response = self.command(READ_VOLTAGE, 0)?;
+        let integer_part = u16::from_be_bytes([response[1], response[2]]);
         let decimal = response[3];
         let voltage = f32::from(integer_part) + f32::from(decimal) / 10.;
         Ok(voltage)
     }
 
-    pub fn read_power(&mut self) -> std::io::Result<u32> {
-        let read_power_packet = [0xB2, 0xC0, 0xA8, 0x01, 0x01, 0x00, 0x1C];
-        let response = self.send_request_get_response(read_power_packet)?;
+    pub fn read_power(&mut self) -> Result<u32, Error> {
+        let response = self.command(READ_POWER, 0)?;
         let power = u32::from_le_bytes([response[2], response[1], 0, 0]);
         Ok(power)
     }
-    pub fn read_current(&mut self) -> std::io::Result<f32> {
-        let read_power_packet = [0xB1, 0xC0, 0xA8, 0x01, 0x01, 0x00, 0x1B];
-        let response = self.send_request_get_response(read_power_packet)?;
-        let integer = response[2];
+
+    pub fn read_current(&mut self) -> Result<f32, Error> {
+        let response = self.command(READ_CURRENT, 0)?;
+        let integer = u16::from_be_bytes([response[1], response[2]]);
         let decimal = response[3];
-        let current = f32::from(integer) + f32::from(decimal) / 10.;
+        let current = f32::from(integer) + f32::from(decimal) / 100.;
         Ok(current)
     }
+
+    /// Accumulated energy since the last reset (done with the button on the meter)
+    pub fn read_energy_wh(&mut self) -> Result<u32, Error> {
+        let response = self.command(READ_ENERGY, 0)?;
+        let energy = u32::from_be_bytes([0, response[1], response[2], response[3]]);
+        Ok(energy)
+    }
+
+    /// Changes the meter address, from now on this driver talks to `new_address`
+    pub fn set_address(&mut self, new_address: Ipv4Addr) -> Result<(), Error> {
+        // the set address command is sent *to* the new address, whoever is on the line takes it
+        let previous = self.address;
+        self.address = new_address;
+        if let Err(e) = self.command(SET_ADDRESS, 0) {
+            self.address = previous;
+            return Err(e);
+        }
+        log::info!("Pzem address changed from {} to {}", previous, new_address);
+        Ok(())
+    }
+
+    pub fn set_power_alarm_threshold_kw(&mut self, threshold_kw: u8) -> Result<(), Error> {
+        if !POWER_ALARM_THRESHOLD_RANGE_KW.contains(&threshold_kw) {
+            return Err(Error::InvalidArgument(format!(
+                "Power alarm threshold must be in {:?}kW, was: {}kW",
+                POWER_ALARM_THRESHOLD_RANGE_KW, threshold_kw
+            )));
+        }
+        self.command(SET_POWER_ALARM, threshold_kw)?;
+        Ok(())
+    }
+
+    /// Reads everything a v1 meter measures, in the same shape as v3 meters.
+    ///
+    /// v1 meters don't measure frequency (NaN is returned) nor report alarm status (always 0),
+    /// the power factor is derived from the apparent power.
+    pub fn read_data(&mut self) -> Result<Data, Error> {
+        let voltage = self.read_voltage()?;
+        let current = self.read_current()?;
+        let power_w = self.read_power()? as f32;
+        let energy_wh = self.read_energy_wh()?;
+        let apparent_power = voltage * current;
+        let power_factor = if apparent_power > 0. {
+            (power_w / apparent_power).clamp(0., 1.)
+        } else {
+            0.
+        };
+        Ok(Data {
+            voltage,
+            current,
+            power_w,
+            energy_wh,
+            frequency: f32::NAN,
+            power_factor,
+            alarm: 0.,
+        })
+    }
+}
+
+impl<T: Transport> EnergyMeter for Pzem<T> {
+    fn read_data(&mut self) -> Result<Data, Error> {
+        Pzem::read_data(self)
+    }
+
+    fn voltage(&mut self) -> Result<f32, Error> {
+        self.read_voltage()
+    }
+
+    fn current(&mut self) -> Result<f32, Error> {
+        self.read_current()
+    }
+
+    fn power_w(&mut self) -> Result<f32, Error> {
+        Ok(self.read_power()? as f32)
+    }
+
+    fn energy_wh(&mut self) -> Result<u32, Error> {
+        self.read_energy_wh()
+    }
+
+    fn frequency(&mut self) -> Result<f32, Error> {
+        Err(Error::Unsupported("v1 meters don't measure frequency"))
+    }
+
+    fn reset_energy(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "v1 meters can only have their energy reset with the button on the meter",
+        ))
+    }
+}
+
+#[test]
+fn read_data_decodes_replies_and_checks_checksums() {
+    use crate::transport::MemoryTransport;
+    let mut transport = MemoryTransport::new();
+    // replies from the original PZEM-004T v1 protocol description
+    transport.push_reply(&[0xA0, 0x00, 0xE6, 0x02, 0x00, 0x00, 0x88]);
+    transport.push_reply(&[0xA1, 0x00, 0x11, 0x20, 0x00, 0x00, 0xD2]);
+    transport.push_reply(&[0xA2, 0x08, 0x98, 0x00, 0x00, 0x00, 0x42]);
+    transport.push_reply(&[0xA3, 0x01, 0x86, 0x9F, 0x00, 0x00, 0xC9]);
+    transport.push_reply(&[0xA0, 0x00, 0xE6, 0x02, 0x00, 0x00, 0x00]);
+    let mut pzem = Pzem::new(transport);
+    let data = pzem.read_data().unwrap();
+    assert_eq!(data.voltage, 230.2);
+    assert_eq!(data.current, 17.32);
+    assert_eq!(data.power_w, 2200.);
+    assert_eq!(data.energy_wh, 99_999);
+    assert_eq!(
+        pzem.uart.written[0],
+        vec![0xB0, 0xC0, 0xA8, 0x01, 0x01, 0x00, 0x1A]
+    );
+    assert!(matches!(pzem.read_voltage(), Err(Error::Crc)));
+    assert!(matches!(
+        pzem.send_request_get_response([0x05, 0, 0, 0, 0, 0, 0x05]),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert_eq!(pzem.uart.written.len(), 5);
 }
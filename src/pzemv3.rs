@@ -1,172 +1,321 @@
+use crate::error::Error;
+use crate::meter::EnergyMeter;
+use crate::modbus::{Master, Request, Response, RtuMaster};
+use crate::transport::Transport;
 use log;
-use serialport::ClearBuffer;
 use std::fmt::Formatter;
-use std::io::{Error, ErrorKind, Read, Write};
+use std::ops::RangeInclusive;
 use std::time::Duration;
 
-pub struct Pzem {
-    uart: Box<dyn serialport::SerialPort>,
+/// Address every PZEM answers to when it's the only one on the line (factory default)
+pub const DEFAULT_ADDRESS: u8 = 0x01;
+const RESET_ENERGY: u8 = 0x42;
+/// Energy the meter may accumulate between the reset and reading it back, 1Wh is ~1.6s at 2.2kW
+const RESET_TOLERANCE_WH: u32 = 1;
+/// Voltage register, first of the 10 measurement input registers
+const FIRST_MEASUREMENT_REGISTER: u16 = 0x0000;
+const MEASUREMENT_REGISTER_COUNT: u16 = 0x000A;
+/// Holding register, 1LSB = 1W
+const POWER_ALARM_THRESHOLD_REGISTER: u16 = 0x0001;
+/// Holding register with the Modbus-RTU slave address
+const ADDRESS_REGISTER: u16 = 0x0002;
+/// Valid slave addresses, 0x00 is broadcast and 0xF8 is the general address
+pub const ADDRESS_RANGE: RangeInclusive<u8> = 0x01..=0xF7;
+/// Active power measuring range of the PZEM-004T-100A, the 10A version only goes to 2.3kW
+pub const POWER_ALARM_THRESHOLD_RANGE_W: RangeInclusive<u16> = 0..=23_000;
+
+mod bus;
+
+pub use crate::meter::Data;
+pub use bus::{Bus, BusMaster};
+
+pub struct Pzem<M: Master = RtuMaster<Box<dyn serialport::SerialPort>>> {
+    master: M,
+    address: u8,
 }
 
-impl std::fmt::Debug for Pzem {
+impl<M: Master> std::fmt::Debug for Pzem<M> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Pzem")
-            .field("uart", &"Box<dyn serialport::SerialPort>")
+            .field("master", &"dyn Master")
+            .field("address", &self.address)
             .finish()
     }
 }
 
+/// How hard to try before giving up on an operation the meter is known to be flaky with
 #[derive(Debug, Clone)]
-pub struct Data {
-    pub voltage: f32,
-    pub current: f32,
-    pub power_w: f32,
-    pub energy_wh: u32,
-    pub frequency: f32,
-    pub power_factor: f32,
-    pub alarm: f32,
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Wait before the second attempt, doubled after each failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
 }
 
-impl Pzem {
-    pub fn new<T: Into<Box<dyn serialport::SerialPort>>>(serialport: T) -> Self {
+impl Default for RetryPolicy {
+    fn default() -> Self {
         Self {
-            uart: serialport.into(),
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(3),
         }
     }
+}
 
-    /// This is not reliable, it works most of the time, but sometimes just fails.. and retries
-    /// dont help, I think the whole pzem crashes
-    pub fn unreliable_reset_consumed_energy(&mut self) -> Result<(), Error> {
-        log::info!("Clearing buffers");
-        self.uart.clear(ClearBuffer::All)?;
-        let mut request = [0x01, 0x42, 0x0, 0x0];
-        Self::crc_write(request.as_mut_slice());
-        log::info!("Writing energy reset request");
-        self.uart.write_all(&request)?;
-        log::info!("Flushing it");
-        self.uart.flush()?;
-        let mut response = [0; 4];
-        log::info!("Reading response");
-        self.uart.read_exact(&mut response)?;
-        if response[1] != 0x42 {
-            log::error!("Error, clearing all buffers");
-            // wait for buffer to fill
-            std::thread::sleep(Duration::from_millis(100));
-            self.uart.clear(ClearBuffer::All)?;
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Internal Pzem Error Code: {}", response[2]),
-            ));
+#[derive(Debug)]
+pub enum ResetOutcome {
+    /// The energy register was read back as zero
+    Confirmed { attempts: u32 },
+    /// Every attempt failed or the error wasn't worth retrying, holds the last error
+    GaveUp { attempts: u32, last_error: Error },
+}
+
+impl ResetOutcome {
+    pub fn into_result(self) -> Result<(), Error> {
+        match self {
+            ResetOutcome::Confirmed { .. } => Ok(()),
+            ResetOutcome::GaveUp { last_error, .. } => Err(last_error),
         }
-        if !Self::crc_is_valid(&response) {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Pzem returned an invalid CRC value"),
-            ));
+    }
+}
+
+fn validate_address(address: u8) -> Result<(), Error> {
+    if !ADDRESS_RANGE.contains(&address) {
+        return Err(Error::InvalidArgument(format!(
+            "Slave address must be in {:#04x}..={:#04x}, was: {:#04x}",
+            ADDRESS_RANGE.start(),
+            ADDRESS_RANGE.end(),
+            address
+        )));
+    }
+    Ok(())
+}
+
+impl Data {
+    /// Decodes the 10 measurement input registers (0x0000 to 0x0009).
+    /// 32 bit values are sent as low word first.
+    fn from_registers(r: &[u16]) -> Data {
+        let u32_from_words = |low: u16, high: u16| (u32::from(high) << 16) | u32::from(low);
+        Data {
+            voltage: r[0] as f32 / 10.,
+            current: u32_from_words(r[1], r[2]) as f32 / 1000.,
+            power_w: u32_from_words(r[3], r[4]) as f32 / 10.,
+            energy_wh: u32_from_words(r[5], r[6]),
+            frequency: r[7] as f32 / 10.,
+            power_factor: r[8] as f32 / 100.,
+            alarm: r[9] as f32,
         }
-        log::info!("Reading energy data to make sure reset worked");
-        if self.read_data()?.energy_wh != 0 {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Pzem returned a non zero energy value after reset"),
-            ));
+    }
+}
+
+impl<T: Transport> Pzem<RtuMaster<T>> {
+    /// Pzem alone on its own line, at the factory default address
+    pub fn new(transport: T) -> Self {
+        Self::with_master(RtuMaster::new(transport), DEFAULT_ADDRESS)
+    }
+}
+
+impl<M: Master> Pzem<M> {
+    pub fn with_master(master: M, address: u8) -> Self {
+        Self { master, address }
+    }
+
+    /// Zeroes the energy counter, retrying according to `policy` until a read back confirms it.
+    ///
+    /// The meter sometimes doesn't answer the reset, or answers garbage, and takes a while to
+    /// come back, so before each attempt the line is resynchronized and the reset is only
+    /// considered done once the energy register reads (close to) zero. A lost reply doesn't
+    /// mean the reset didn't happen, so the energy is checked even when the reset failed.
+    pub fn reset_energy_with_retries(&mut self, policy: &RetryPolicy) -> ResetOutcome {
+        let request = Request::custom(self.address, RESET_ENERGY, vec![]);
+        let mut backoff = policy.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            log::info!("Energy reset attempt {}/{}", attempts, policy.max_attempts);
+            let error = match self.try_reset_energy(&request) {
+                Ok(()) => {
+                    log::info!("Energy reset confirmed after {} attempt(s)", attempts);
+                    return ResetOutcome::Confirmed { attempts };
+                }
+                Err(e) => e,
+            };
+            log::warn!("Energy reset attempt {} failed: {}", attempts, error);
+            if !error.is_retryable() || attempts >= policy.max_attempts {
+                return ResetOutcome::GaveUp {
+                    attempts,
+                    last_error: error,
+                };
+            }
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(policy.max_backoff);
+        }
+    }
+
+    fn try_reset_energy(&mut self, request: &Request) -> Result<(), Error> {
+        self.master.resynchronize()?;
+        let reset_error = match self.master.transact(request) {
+            Ok(_) => None,
+            Err(e) if !e.is_retryable() => return Err(e),
+            Err(e) => {
+                log::warn!("No proper reply to energy reset, checking if it happened anyway");
+                // give the meter some time to come back before asking again
+                std::thread::sleep(Duration::from_millis(100));
+                self.master.resynchronize()?;
+                Some(e)
+            }
+        };
+        let energy_wh = self.read_data()?.energy_wh;
+        if energy_wh > RESET_TOLERANCE_WH {
+            return Err(reset_error.unwrap_or_else(|| {
+                Error::UnexpectedReply(format!("Pzem returned {}Wh after energy reset", energy_wh))
+            }));
         }
-        log::info!("Energy reset worked");
         Ok(())
     }
 
     pub fn read_data(&mut self) -> Result<Data, Error> {
-        self.uart.clear(ClearBuffer::All)?;
-        // [Slave Address (0x01 ~ 0xF7), 0x04, Register Address High Byte, Register Address Low Byte, Number of Registers High Byte, Number of Registers Low Byte, CRC Check High Byte, CRC Check Low Byte]
-        // Read Registers 0 to 11 from slave 1
-        let mut request = [0x01, 0x04, 0x00, 0x00, 0x00, 0x0A, 0x0, 0x0];
-        Self::crc_write(request.as_mut_slice());
-        self.uart.write_all(&request)?;
-        self.uart.flush()?;
-        let mut response = [0; 25];
-        self.uart.read_exact(&mut response[0..3])?;
-
-        // if the second byte isn't 0x04 it means it we got an error and the error
-        // itself is specified by the third byte or corrupt msg
-        if response[1] != 0x04 {
-            self.uart.clear(ClearBuffer::Input)?;
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Internal Pzem Error Code: {}", response[2]),
-            ));
+        let request = Request::read_input_registers(
+            self.address,
+            FIRST_MEASUREMENT_REGISTER,
+            MEASUREMENT_REGISTER_COUNT,
+        );
+        match self.master.transact(&request)? {
+            Response::Registers(registers) => Ok(Data::from_registers(&registers)),
+            other => Err(Error::UnexpectedReply(format!(
+                "Unexpected reply to measurement read: {:?}",
+                other
+            ))),
         }
-        // Since we requested
-        if response[2] != 20 {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Pzem returned a different amount of data than requested. Should have been 20 bytes, was: {}", response[2]),
-            ));
+    }
+
+    /// Address this driver is currently talking to
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn read_power_alarm_threshold_w(&mut self) -> Result<u16, Error> {
+        self.read_holding_register(POWER_ALARM_THRESHOLD_REGISTER)
+    }
+
+    pub fn set_power_alarm_threshold_w(&mut self, threshold_w: u16) -> Result<(), Error> {
+        if !POWER_ALARM_THRESHOLD_RANGE_W.contains(&threshold_w) {
+            return Err(Error::InvalidArgument(format!(
+                "Power alarm threshold must be in {:?}W, was: {}W",
+                POWER_ALARM_THRESHOLD_RANGE_W, threshold_w
+            )));
         }
-        // 2 last bytes are CRC
-        self.uart.read_exact(&mut response[3..])?;
-        if !Self::crc_is_valid(&response) {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Pzem returned an invalid CRC value"),
-            ));
+        self.write_holding_register(POWER_ALARM_THRESHOLD_REGISTER, threshold_w)
+    }
+
+    /// Reads the slave address stored in the meter, should always match [`Pzem::address`]
+    pub fn read_address(&mut self) -> Result<u8, Error> {
+        let address = self.read_holding_register(ADDRESS_REGISTER)?;
+        u8::try_from(address)
+            .ok()
+            .filter(|a| ADDRESS_RANGE.contains(a))
+            .ok_or_else(|| {
+                Error::UnexpectedReply(format!(
+                    "Pzem reported an invalid slave address: {:#06x}",
+                    address
+                ))
+            })
+    }
+
+    /// Changes the meter slave address, from now on this driver talks to `new_address`
+    pub fn set_address(&mut self, new_address: u8) -> Result<(), Error> {
+        validate_address(new_address)?;
+        self.write_holding_register(ADDRESS_REGISTER, u16::from(new_address))?;
+        log::info!(
+            "Pzem address changed from {:#04x} to {:#04x}",
+            self.address,
+            new_address
+        );
+        self.address = new_address;
+        Ok(())
+    }
+
+    fn read_holding_register(&mut self, register: u16) -> Result<u16, Error> {
+        let request = Request::read_holding_registers(self.address, register, 1);
+        match self.master.transact(&request)? {
+            Response::Registers(registers) => Ok(registers[0]),
+            other => Err(Error::UnexpectedReply(format!(
+                "Unexpected reply to holding register read: {:?}",
+                other
+            ))),
         }
-        if self.uart.bytes_to_read()? != 0 {
-            log::warn!("Had bytes leftover in usb line!");
-            self.uart.clear(ClearBuffer::All)?;
+    }
+
+    /// Writes a single holding register and makes sure the meter echoed back what we sent
+    fn write_holding_register(&mut self, register: u16, value: u16) -> Result<(), Error> {
+        let request = Request::write_single_register(self.address, register, value);
+        match self.master.transact(&request)? {
+            Response::WriteSingleRegister {
+                register: echoed_register,
+                value: echoed_value,
+            } if echoed_register == register && echoed_value == value => Ok(()),
+            other => Err(Error::UnexpectedReply(format!(
+                "Pzem did not echo the write of {:#06x} to register {:#06x}: {:?}",
+                value, register, other
+            ))),
         }
-        let response = &response[3..];
-        let data = Data {
-            voltage: u16::from_be_bytes([response[0], response[1]]) as f32 / 10.,
-            current: u32::from_be_bytes([response[4], response[5], response[2], response[3]])
-                as f32
-                / 1000.,
-            power_w: u32::from_be_bytes([response[8], response[9], response[6], response[7]])
-                as f32
-                / 10.,
-            energy_wh: u32::from_be_bytes([response[12], response[13], response[10], response[11]]),
-            frequency: u16::from_be_bytes([response[14], response[15]]) as f32 / 10.,
-            power_factor: u16::from_be_bytes([response[16], response[17]]) as f32 / 100.,
-            alarm: u16::from_be_bytes([response[18], response[19]]) as f32,
-        };
-        Ok(data)
-    }
-
-    // 16-bit cyclic redundancy check (CRC).
-    fn crc_write(buf: &mut [u8]) {
-        let n = buf.len();
-        assert!(n > 3, "Need at least 3 bytes to calculate the CRC check");
-        // this results in the bytes possibly "inverted", we later make it big endian
-        let crc = crc::Crc::<u16>::new(&crc::CRC_16_MODBUS);
-        let mut digest = crc.digest();
-        digest.update(&buf[0..n - 2]);
-        let final_res = digest.finalize().to_be().to_be_bytes();
-        buf[n - 2] = final_res[0];
-        buf[n - 1] = final_res[1];
-    }
-
-    // Returns true if the CRC is valid
-    fn crc_is_valid(buf: &[u8]) -> bool {
-        let n = buf.len();
-        assert!(n > 3, "Need at least 3 bytes to calculate the CRC check");
-        let crc = crc::Crc::<u16>::new(&crc::CRC_16_MODBUS);
-        let mut digest = crc.digest();
-        digest.update(&buf[0..n - 2]);
-        let final_res = digest.finalize().to_be().to_be_bytes();
-        final_res == &buf[n - 2..]
+    }
+}
+
+impl<M: Master> EnergyMeter for Pzem<M> {
+    fn read_data(&mut self) -> Result<Data, Error> {
+        Pzem::read_data(self)
+    }
+
+    fn reset_energy(&mut self) -> Result<(), Error> {
+        self.reset_energy_with_retries(&RetryPolicy::default())
+            .into_result()
     }
 }
 
 #[test]
-fn crc_work() {
-    let correct_message_with_crc = [
-        0x01, 0x4, 0x14, 0x4, 0xEB, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 2, 0x57, 0, 0, 0, 0, 0x18,
-        0xB8,
-    ];
-    assert!(Pzem::crc_is_valid(&correct_message_with_crc));
-    let mut msg = [
-        0x01, 0x4, 0x14, 0x4, 0xEB, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 2, 0x57, 0, 0, 0, 0, 0, 0,
+fn read_data_decodes_registers() {
+    use crate::transport::MemoryTransport;
+    // 230.0V, 1.000A, 230.0W, 70000Wh, 60.0Hz, 0.98 PF, no alarm
+    let mut reply = vec![
+        0x01, 0x04, 0x14, 0x08, 0xFC, 0x03, 0xE8, 0x00, 0x00, 0x08, 0xFC, 0x00, 0x00, 0x11, 0x70,
+        0x00, 0x01, 0x02, 0x58, 0x00, 0x62, 0x00, 0x00, 0, 0,
     ];
-    Pzem::crc_write(&mut msg);
-    let len = msg.len();
-    assert_eq!(&correct_message_with_crc[len - 2..], &msg[len - 2..]);
+    crate::modbus::crc_write(&mut reply);
+    let mut transport = MemoryTransport::new();
+    transport.push_reply(&reply);
+    let mut pzem = Pzem::new(transport);
+    let data = pzem.read_data().unwrap();
+    assert_eq!(data.voltage, 230.);
+    assert_eq!(data.current, 1.);
+    assert_eq!(data.power_w, 230.);
+    assert_eq!(data.energy_wh, 70_000);
+    assert_eq!(data.frequency, 60.);
+    assert_eq!(data.power_factor, 0.98);
+    assert_eq!(
+        pzem.master.transport().written[0][..6],
+        [0x01, 0x04, 0x00, 0x00, 0x00, 0x0A]
+    );
+}
+
+#[test]
+fn set_address_validates_and_follows_new_address() {
+    use crate::transport::MemoryTransport;
+    let request = Request::write_single_register(0x01, ADDRESS_REGISTER, 0x05);
+    let mut transport = MemoryTransport::new();
+    // the meter echoes the write back from its old address
+    transport.push_reply(&request.to_frame());
+    let mut pzem = Pzem::new(transport);
+    assert!(matches!(
+        pzem.set_address(0xF8),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        pzem.set_power_alarm_threshold_w(30_000),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(pzem.master.transport().written.is_empty());
+    pzem.set_address(0x05).unwrap();
+    assert_eq!(pzem.address(), 0x05);
+    assert_eq!(pzem.master.transport().written[0], request.to_frame());
 }
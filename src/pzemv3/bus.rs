@@ -0,0 +1,110 @@
+use super::{validate_address, Pzem, ADDRESS_RANGE, ADDRESS_REGISTER};
+use crate::error::Error;
+use crate::modbus::{Master, Request, Response, RtuMaster};
+use crate::transport::Transport;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+/// Several PZEMs sharing one RS-485/TTL line, each configured with its own slave address.
+///
+/// The bus owns the transport and hands out [`Pzem`] handles for each address, a whole
+/// request/reply exchange happens while holding the bus lock so handles can be used from
+/// different threads without their frames getting mixed on the line.
+#[derive(Debug)]
+pub struct Bus<T: Transport> {
+    master: Arc<Mutex<RtuMaster<T>>>,
+}
+
+/// [`Master`] shared by all meters handed out by a [`Bus`]
+#[derive(Debug)]
+pub struct BusMaster<T: Transport> {
+    master: Arc<Mutex<RtuMaster<T>>>,
+}
+
+impl<T: Transport> Clone for BusMaster<T> {
+    fn clone(&self) -> Self {
+        Self {
+            master: self.master.clone(),
+        }
+    }
+}
+
+impl<T: Transport> Master for BusMaster<T> {
+    fn transact(&mut self, request: &Request) -> Result<Response, Error> {
+        self.master
+            .lock()
+            .expect("Pzem bus lock poisoned")
+            .transact(request)
+    }
+
+    fn resynchronize(&mut self) -> Result<(), Error> {
+        self.master
+            .lock()
+            .expect("Pzem bus lock poisoned")
+            .resynchronize()
+    }
+}
+
+impl<T: Transport> Bus<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            master: Arc::new(Mutex::new(RtuMaster::new(transport))),
+        }
+    }
+
+    /// Handle to the meter configured with `address`, nothing is sent on the line.
+    pub fn meter(&self, address: u8) -> Result<Pzem<BusMaster<T>>, Error> {
+        validate_address(address)?;
+        let master = BusMaster {
+            master: self.master.clone(),
+        };
+        Ok(Pzem::with_master(master, address))
+    }
+
+    /// Probes every valid slave address (0x01 to 0xF7) and returns the ones that answered.
+    ///
+    /// Each silent address costs a full read timeout, so with the usual 200ms this takes
+    /// close to a minute, see [`Bus::scan_range`] to probe fewer addresses.
+    pub fn scan(&self) -> Vec<u8> {
+        self.scan_range(ADDRESS_RANGE)
+    }
+
+    pub fn scan_range(&self, addresses: RangeInclusive<u8>) -> Vec<u8> {
+        let mut master = self.master.lock().expect("Pzem bus lock poisoned");
+        addresses
+            .filter(|address| ADDRESS_RANGE.contains(address))
+            .filter(|&address| {
+                let request = Request::read_holding_registers(address, ADDRESS_REGISTER, 1);
+                match master.transact(&request) {
+                    Ok(_) => true,
+                    // a Modbus exception still means someone is listening on this address
+                    Err(Error::Exception(_)) => true,
+                    Err(e) => {
+                        log::debug!("No reply from {:#04x}: {}", address, e);
+                        false
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn scan_finds_responding_addresses() {
+    use crate::modbus::crc_write;
+    use crate::transport::MemoryTransport;
+    let mut transport = MemoryTransport::new();
+    let mut reply_from_2 = vec![0x02, 0x03, 0x02, 0x00, 0x02, 0, 0];
+    crc_write(&mut reply_from_2);
+    let mut exception_from_4 = vec![0x04, 0x83, 0x02, 0, 0];
+    crc_write(&mut exception_from_4);
+    transport.push_reply(&[]);
+    transport.push_reply(&reply_from_2);
+    transport.push_reply(&[]);
+    transport.push_reply(&exception_from_4);
+    let bus = Bus::new(transport);
+    assert_eq!(bus.scan_range(0x00..=0x04), vec![0x02, 0x04]);
+    assert!(bus.meter(0xF8).is_err());
+    let meter = bus.meter(0x02).unwrap();
+    assert_eq!(meter.address(), 0x02);
+}
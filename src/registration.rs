@@ -0,0 +1,136 @@
+use crate::database::{Database, PendingUser};
+use crate::telegram::{Buttons, OutgoingMessage, UserMessage};
+use std::sync::mpsc::SyncSender;
+
+fn reply(user_message: &UserMessage, text: String) -> OutgoingMessage {
+    OutgoingMessage {
+        update_message_with_id: None,
+        chat_id: user_message.chat_id,
+        text,
+        buttons: Buttons::None,
+    }
+}
+
+/// Saves the request of someone unknown and asks every admin to approve or reject it
+pub async fn request(
+    database: &Database,
+    telegram_sender: &SyncSender<OutgoingMessage>,
+    admin_chat_ids: &[i64],
+    user_message: &UserMessage,
+    name: Option<String>,
+) -> OutgoingMessage {
+    let name = name.unwrap_or_else(|| user_message.user_name.clone());
+    if let Err(e) = database
+        .request_registration(user_message.user_id, user_message.chat_id, &name)
+        .await
+    {
+        log::error!("Error saving the registration request: {:#?}", e);
+        return reply(
+            user_message,
+            "Problema na rede interna da casa, fale com @TiberioFerreira".to_string(),
+        );
+    }
+    log::info!(
+        "{} ({}) asked to register as {}",
+        user_message.user_name,
+        user_message.user_id,
+        name
+    );
+    if admin_chat_ids.is_empty() {
+        log::warn!("Nobody to approve the registration of {}", name);
+    }
+    for admin_chat_id in admin_chat_ids {
+        let request = OutgoingMessage {
+            update_message_with_id: None,
+            chat_id: *admin_chat_id,
+            text: format!(
+                "{} (perfil: {}, id: {}) pediu para usar a secadora.",
+                name, user_message.user_name, user_message.user_id
+            ),
+            buttons: Buttons::Registration {
+                telegram_id: user_message.user_id,
+            },
+        };
+        if let Err(e) = telegram_sender.try_send(request) {
+            log::error!("{:#?}", e);
+        }
+    }
+    reply(
+        user_message,
+        format!(
+            "Pedido de registro como {} enviado, você será avisado quando for aprovado.",
+            name
+        ),
+    )
+}
+
+/// An admin pressed approve or reject, the requester is told the outcome and the request
+/// message is replaced by it
+pub async fn answer(
+    database: &Database,
+    telegram_sender: &SyncSender<OutgoingMessage>,
+    is_admin: bool,
+    user_message: &UserMessage,
+    telegram_id: u64,
+    approve: bool,
+) -> OutgoingMessage {
+    if !is_admin {
+        log::warn!(
+            "{} ({}) tried to answer the registration of {} without being an admin",
+            user_message.user_name,
+            user_message.user_id,
+            telegram_id
+        );
+        return reply(
+            user_message,
+            "Só administradores podem aprovar registros.".to_string(),
+        );
+    }
+    let result = if approve {
+        database.approve_registration(telegram_id).await
+    } else {
+        database.reject_registration(telegram_id).await
+    };
+    let text = match result {
+        Ok(Some(PendingUser { chat_id, name, .. })) => {
+            let (admin_text, user_text) = if approve {
+                (
+                    format!("{} aprovado por {}.", name, user_message.user_name),
+                    "Seu registro foi aprovado! Carregue saldo para usar a secadora.",
+                )
+            } else {
+                (
+                    format!("{} rejeitado por {}.", name, user_message.user_name),
+                    "Seu pedido de registro foi rejeitado.",
+                )
+            };
+            log::info!("{}", admin_text);
+            let notification = OutgoingMessage {
+                update_message_with_id: None,
+                chat_id,
+                text: user_text.to_string(),
+                buttons: if approve {
+                    Buttons::Dryer
+                } else {
+                    Buttons::None
+                },
+            };
+            if let Err(e) = telegram_sender.try_send(notification) {
+                log::error!("{:#?}", e);
+            }
+            admin_text
+        }
+        Ok(None) => format!(
+            "Nenhum pedido de registro pendente para o id {}, talvez já tenha sido respondido.",
+            telegram_id
+        ),
+        Err(e) => {
+            log::error!("Error answering the registration request: {:#?}", e);
+            "Erro ao acessar o banco de dados, tente novamente.".to_string()
+        }
+    };
+    OutgoingMessage {
+        update_message_with_id: user_message.message_id,
+        ..reply(user_message, text)
+    }
+}
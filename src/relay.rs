@@ -0,0 +1,366 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod async_relay;
+
+pub use async_relay::AsyncRelay;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayState {
+    On,
+    Off,
+}
+
+/// Everything that can go wrong switching a relay
+#[derive(Debug)]
+pub enum Error {
+    Gpio(gpio_cdev::Error),
+    /// The smart plug could not be reached or answered with an HTTP error
+    Http(Box<ureq::Error>),
+    /// The device answered something we don't understand, or a state other than the one asked
+    UnexpectedReply(String),
+    /// The I/O thread of an [`AsyncRelay`] is gone
+    Disconnected,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Gpio(e) => write!(f, "Relay GPIO error: {}", e),
+            Error::Http(e) => write!(f, "Smart plug request failed: {}", e),
+            Error::UnexpectedReply(msg) => write!(f, "Unexpected relay reply: {}", msg),
+            Error::Disconnected => write!(f, "Relay I/O thread is gone"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Gpio(e) => Some(e),
+            Error::Http(e) => Some(e),
+            Error::UnexpectedReply(_) | Error::Disconnected => None,
+        }
+    }
+}
+
+impl From<gpio_cdev::Error> for Error {
+    fn from(e: gpio_cdev::Error) -> Self {
+        Error::Gpio(e)
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        Error::Http(Box::new(e))
+    }
+}
+
+/// Whatever powers the dryer on and off: an SSR on a GPIO line, a smart plug...
+///
+/// Implementations may block, from async code go through an [`AsyncRelay`].
+pub trait Relay {
+    fn turn_on(&mut self) -> Result<(), Error>;
+    fn turn_off(&mut self) -> Result<(), Error>;
+    /// What the relay is being told to do, not whether current is actually flowing
+    fn state(&mut self) -> Result<RelayState, Error>;
+}
+
+impl<R: Relay + ?Sized> Relay for Box<R> {
+    fn turn_on(&mut self) -> Result<(), Error> {
+        (**self).turn_on()
+    }
+
+    fn turn_off(&mut self) -> Result<(), Error> {
+        (**self).turn_off()
+    }
+
+    fn state(&mut self) -> Result<RelayState, Error> {
+        (**self).state()
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    state: RelayState,
+    failing: bool,
+    switches: u32,
+}
+
+/// In-memory relay for tests, clones share the same relay so tests can look at it after
+/// handing it over
+#[derive(Debug, Clone)]
+pub struct MockRelay {
+    inner: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockRelay {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(MockState {
+                state: RelayState::Off,
+                failing: false,
+                switches: 0,
+            })),
+        }
+    }
+}
+
+impl MockRelay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// While failing, every operation returns an error and the state doesn't change
+    pub fn set_failing(&self, failing: bool) {
+        self.inner.lock().expect("MockRelay lock poisoned").failing = failing;
+    }
+
+    pub fn current_state(&self) -> RelayState {
+        self.inner.lock().expect("MockRelay lock poisoned").state
+    }
+
+    /// How many times the relay was successfully switched, on or off
+    pub fn switches(&self) -> u32 {
+        self.inner.lock().expect("MockRelay lock poisoned").switches
+    }
+
+    fn set(&mut self, state: RelayState) -> Result<(), Error> {
+        let mut inner = self.inner.lock().expect("MockRelay lock poisoned");
+        if inner.failing {
+            return Err(Error::UnexpectedReply("Mock relay set to fail".to_string()));
+        }
+        inner.state = state;
+        inner.switches += 1;
+        Ok(())
+    }
+}
+
+impl Relay for MockRelay {
+    fn turn_on(&mut self) -> Result<(), Error> {
+        self.set(RelayState::On)
+    }
+
+    fn turn_off(&mut self) -> Result<(), Error> {
+        self.set(RelayState::Off)
+    }
+
+    fn state(&mut self) -> Result<RelayState, Error> {
+        let inner = self.inner.lock().expect("MockRelay lock poisoned");
+        if inner.failing {
+            return Err(Error::UnexpectedReply("Mock relay set to fail".to_string()));
+        }
+        Ok(inner.state)
+    }
+}
+
+/// Smart plug firmwares spoken over their local HTTP API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmartPlugKind {
+    /// `/cm?cmnd=Power<n>`, replies `{"POWER":"ON"}` (`POWER<n>` on multi relay devices)
+    Tasmota { relay: u8 },
+    /// Gen 1 `/relay/<n>?turn=on`, replies `{"ison":true,...}`
+    Shelly { relay: u8 },
+}
+
+/// Relay inside a smart plug on the local network, for machines without an SSR wired to a Pi.
+///
+/// Parsed from `KIND[:RELAY]@URL`, like `tasmota@http://10.0.0.5` or `shelly:1@http://10.0.0.7`,
+/// relays count from 1 for Tasmota and from 0 for Shelly, as their own APIs do.
+#[derive(Debug)]
+pub struct SmartPlug {
+    agent: ureq::Agent,
+    base_url: String,
+    kind: SmartPlugKind,
+}
+
+impl SmartPlug {
+    pub fn new(base_url: &str, kind: SmartPlugKind, timeout: Duration) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            kind,
+        }
+    }
+
+    /// `None` just asks for the state
+    fn request(&self, turn_on: Option<bool>) -> Result<RelayState, Error> {
+        let request = match self.kind {
+            SmartPlugKind::Tasmota { relay } => {
+                let command = match turn_on {
+                    None => format!("Power{}", relay),
+                    Some(true) => format!("Power{} On", relay),
+                    Some(false) => format!("Power{} Off", relay),
+                };
+                self.agent
+                    .get(&format!("{}/cm", self.base_url))
+                    .query("cmnd", &command)
+            }
+            SmartPlugKind::Shelly { relay } => {
+                let request = self
+                    .agent
+                    .get(&format!("{}/relay/{}", self.base_url, relay));
+                match turn_on {
+                    None => request,
+                    Some(true) => request.query("turn", "on"),
+                    Some(false) => request.query("turn", "off"),
+                }
+            }
+        };
+        let reply = request
+            .call()?
+            .into_string()
+            .map_err(|e| Error::UnexpectedReply(format!("Unreadable reply: {}", e)))?;
+        parse_smart_plug_reply(self.kind, &reply)
+    }
+
+    fn switch(&self, on: bool) -> Result<(), Error> {
+        let expected = if on { RelayState::On } else { RelayState::Off };
+        let state = self.request(Some(on))?;
+        if state != expected {
+            return Err(Error::UnexpectedReply(format!(
+                "Asked smart plug {} to be {:?}, it is {:?}",
+                self.base_url, expected, state
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SmartPlug {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, url) = s
+            .split_once('@')
+            .ok_or_else(|| format!("Expected KIND[:RELAY]@URL, got `{}`", s))?;
+        let (name, relay) = match kind.split_once(':') {
+            None => (kind, None),
+            Some((name, relay)) => {
+                let relay = relay
+                    .parse()
+                    .map_err(|e| format!("Invalid relay `{}` in `{}`: {}", relay, s, e))?;
+                (name, Some(relay))
+            }
+        };
+        let kind = match name {
+            "tasmota" => SmartPlugKind::Tasmota {
+                relay: relay.unwrap_or(1),
+            },
+            "shelly" => SmartPlugKind::Shelly {
+                relay: relay.unwrap_or(0),
+            },
+            _ => return Err(format!("Unknown smart plug kind `{}` in `{}`", name, s)),
+        };
+        Ok(Self::new(url, kind, Duration::from_secs(5)))
+    }
+}
+
+fn parse_smart_plug_reply(kind: SmartPlugKind, reply: &str) -> Result<RelayState, Error> {
+    let json: serde_json::Value = serde_json::from_str(reply)
+        .map_err(|e| Error::UnexpectedReply(format!("`{}` is not JSON: {}", reply, e)))?;
+    let unexpected = || Error::UnexpectedReply(format!("No relay state in `{}`", reply));
+    match kind {
+        SmartPlugKind::Tasmota { relay } => {
+            // single relay devices answer POWER, multi relay ones POWER<n>
+            let power = json
+                .get(format!("POWER{}", relay))
+                .or_else(|| json.get("POWER"))
+                .and_then(|power| power.as_str())
+                .ok_or_else(unexpected)?;
+            match power {
+                "ON" => Ok(RelayState::On),
+                "OFF" => Ok(RelayState::Off),
+                _ => Err(unexpected()),
+            }
+        }
+        SmartPlugKind::Shelly { .. } => match json.get("ison").and_then(|on| on.as_bool()) {
+            Some(true) => Ok(RelayState::On),
+            Some(false) => Ok(RelayState::Off),
+            None => Err(unexpected()),
+        },
+    }
+}
+
+impl Relay for SmartPlug {
+    fn turn_on(&mut self) -> Result<(), Error> {
+        self.switch(true)
+    }
+
+    fn turn_off(&mut self) -> Result<(), Error> {
+        self.switch(false)
+    }
+
+    fn state(&mut self) -> Result<RelayState, Error> {
+        self.request(None)
+    }
+}
+
+#[test]
+fn smart_plug_replies_are_parsed() {
+    let tasmota = SmartPlugKind::Tasmota { relay: 1 };
+    assert_eq!(
+        parse_smart_plug_reply(tasmota, r#"{"POWER":"ON"}"#).unwrap(),
+        RelayState::On
+    );
+    assert_eq!(
+        parse_smart_plug_reply(tasmota, r#"{"POWER1":"OFF"}"#).unwrap(),
+        RelayState::Off
+    );
+    assert!(parse_smart_plug_reply(tasmota, r#"{"Command":"Unknown"}"#).is_err());
+    let shelly = SmartPlugKind::Shelly { relay: 0 };
+    assert_eq!(
+        parse_smart_plug_reply(shelly, r#"{"ison":true,"has_timer":false}"#).unwrap(),
+        RelayState::On
+    );
+    assert!(parse_smart_plug_reply(shelly, "<html>").is_err());
+
+    let plug: SmartPlug = "shelly:1@http://10.0.0.7/".parse().unwrap();
+    assert_eq!(plug.kind, SmartPlugKind::Shelly { relay: 1 });
+    assert_eq!(plug.base_url, "http://10.0.0.7");
+    assert!("sonoff@http://10.0.0.7".parse::<SmartPlug>().is_err());
+}
+
+#[test]
+fn smart_plug_talks_http() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut request_line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut request_line)
+            .unwrap();
+        let body = r#"{"POWER":"ON"}"#;
+        write!(
+            &stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+        request_line
+    });
+    let mut plug: SmartPlug = format!("tasmota@http://{}", address).parse().unwrap();
+    plug.turn_on().unwrap();
+    assert!(server
+        .join()
+        .unwrap()
+        .starts_with("GET /cm?cmnd=Power1+On "));
+}
+
+#[test]
+fn mock_relay_is_shared_between_clones() {
+    let relay = MockRelay::new();
+    let mut handed_over = relay.clone();
+    handed_over.turn_on().unwrap();
+    assert_eq!(relay.current_state(), RelayState::On);
+    relay.set_failing(true);
+    assert!(handed_over.turn_off().is_err());
+    assert_eq!(relay.current_state(), RelayState::On);
+    assert_eq!(relay.switches(), 1);
+}
@@ -0,0 +1,51 @@
+use super::{Error, Relay, RelayState};
+use crate::io_thread::IoThread;
+
+/// Async handle to a relay living in its own I/O thread, see [`IoThread`], smart plugs are
+/// switched over blocking HTTP. Handles are cheap to clone and all share the same relay.
+#[derive(Debug, Clone)]
+pub struct AsyncRelay {
+    io: IoThread<Box<dyn Relay + Send>>,
+}
+
+impl AsyncRelay {
+    pub fn spawn<R: Relay + Send + 'static>(relay: R) -> Self {
+        Self {
+            io: IoThread::spawn("Relay", Box::new(relay)),
+        }
+    }
+
+    pub async fn turn_on(&self) -> Result<(), Error> {
+        self.io
+            .run(|relay| relay.turn_on())
+            .await
+            .unwrap_or(Err(Error::Disconnected))
+    }
+
+    pub async fn turn_off(&self) -> Result<(), Error> {
+        self.io
+            .run(|relay| relay.turn_off())
+            .await
+            .unwrap_or(Err(Error::Disconnected))
+    }
+
+    pub async fn state(&self) -> Result<RelayState, Error> {
+        self.io
+            .run(|relay| relay.state())
+            .await
+            .unwrap_or(Err(Error::Disconnected))
+    }
+}
+
+#[tokio::test]
+async fn async_relay_switches_the_relay_from_its_own_thread() {
+    use super::MockRelay;
+    let mock = MockRelay::new();
+    let relay = AsyncRelay::spawn(mock.clone());
+    relay.turn_on().await.unwrap();
+    assert_eq!(mock.current_state(), RelayState::On);
+    assert_eq!(relay.clone().state().await.unwrap(), RelayState::On);
+    mock.set_failing(true);
+    assert!(relay.turn_off().await.is_err());
+    assert_eq!(mock.current_state(), RelayState::On);
+}
@@ -0,0 +1,269 @@
+use crate::transport::Transport;
+use serialport::SerialPortType;
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Where udev puts stable names for serial devices, based on their USB descriptors
+pub const BY_ID_DIR: &str = "/dev/serial/by-id";
+/// Minimum time between attempts at reopening a port that went away
+const REOPEN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How to find the serial port a meter is on.
+///
+/// USB serial adapters get a new `ttyUSBn` name whenever they re-enumerate (after a brownout,
+/// for instance), so a fixed path only works for ports that are not USB.
+/// Parsed from `usb:VID:PID[:SERIAL]` (hex ids), `by-id:NAME` or a plain path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortLocator {
+    Path(PathBuf),
+    /// Name of the symlink in [`BY_ID_DIR`]
+    ById(String),
+    UsbId {
+        vid: u16,
+        pid: u16,
+        serial_number: Option<String>,
+    },
+}
+
+impl PortLocator {
+    /// Path of the port right now, fails with `NotFound` if the device is not there
+    pub fn locate(&self) -> std::io::Result<String> {
+        match self {
+            PortLocator::Path(path) => Self::existing(path),
+            PortLocator::ById(name) => Self::existing(&Path::new(BY_ID_DIR).join(name)),
+            PortLocator::UsbId {
+                vid,
+                pid,
+                serial_number,
+            } => serialport::available_ports()?
+                .into_iter()
+                .find(|port| match &port.port_type {
+                    SerialPortType::UsbPort(usb) => {
+                        usb.vid == *vid
+                            && usb.pid == *pid
+                            && (serial_number.is_none() || *serial_number == usb.serial_number)
+                    }
+                    _ => false,
+                })
+                .map(|port| port.port_name)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No {} connected", self))),
+        }
+    }
+
+    fn existing(path: &Path) -> std::io::Result<String> {
+        if !path.exists() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ));
+        }
+        Ok(path.to_string_lossy().into_owned())
+    }
+}
+
+impl Display for PortLocator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortLocator::Path(path) => write!(f, "{}", path.display()),
+            PortLocator::ById(name) => write!(f, "by-id:{}", name),
+            PortLocator::UsbId {
+                vid,
+                pid,
+                serial_number: None,
+            } => write!(f, "usb:{:04x}:{:04x}", vid, pid),
+            PortLocator::UsbId {
+                vid,
+                pid,
+                serial_number: Some(serial_number),
+            } => write!(f, "usb:{:04x}:{:04x}:{}", vid, pid, serial_number),
+        }
+    }
+}
+
+impl FromStr for PortLocator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = s.strip_prefix("by-id:") {
+            return Ok(PortLocator::ById(name.to_string()));
+        }
+        let usb = match s.strip_prefix("usb:") {
+            None => return Ok(PortLocator::Path(PathBuf::from(s))),
+            Some(usb) => usb,
+        };
+        let mut parts = usb.splitn(3, ':');
+        let mut id = |what: &str| {
+            let part = parts.next().unwrap_or_default();
+            u16::from_str_radix(part, 16)
+                .map_err(|e| format!("Invalid USB {} `{}` in `{}`: {}", what, part, s, e))
+        };
+        let vid = id("vendor id")?;
+        let pid = id("product id")?;
+        Ok(PortLocator::UsbId {
+            vid,
+            pid,
+            serial_number: parts.next().map(str::to_string),
+        })
+    }
+}
+
+/// Serial port that finds its device again when it disappears.
+///
+/// Any error other than a read timeout drops the port, the next operation locates and reopens
+/// it (at most once every couple of seconds), failing with `NotConnected` while it is gone.
+/// Drivers on top of it just see failed requests until the device is back.
+pub struct ReconnectingPort {
+    locator: PortLocator,
+    baud_rate: u32,
+    timeout: Duration,
+    port: Option<Box<dyn serialport::SerialPort>>,
+    last_open_attempt: Option<Instant>,
+}
+
+impl std::fmt::Debug for ReconnectingPort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReconnectingPort")
+            .field("locator", &self.locator)
+            .field("baud_rate", &self.baud_rate)
+            .field("timeout", &self.timeout)
+            .field("connected", &self.port.is_some())
+            .finish()
+    }
+}
+
+impl ReconnectingPort {
+    /// Tries to open the port right away, but doesn't fail if it is not there yet
+    pub fn new(locator: PortLocator, baud_rate: u32, timeout: Duration) -> Self {
+        let mut port = Self {
+            locator,
+            baud_rate,
+            timeout,
+            port: None,
+            last_open_attempt: None,
+        };
+        if let Err(e) = port.port() {
+            log::error!("{}", e);
+        }
+        port
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.port.is_some()
+    }
+
+    fn open(&self) -> std::io::Result<Box<dyn serialport::SerialPort>> {
+        let path = self.locator.locate()?;
+        let port = serialport::new(&path, self.baud_rate)
+            .timeout(self.timeout)
+            .open()?;
+        log::info!("Opened serial port {} ({})", path, self.locator);
+        Ok(port)
+    }
+
+    fn port(&mut self) -> std::io::Result<&mut Box<dyn serialport::SerialPort>> {
+        if self.port.is_none() {
+            let too_soon = self
+                .last_open_attempt
+                .is_some_and(|at| at.elapsed() < REOPEN_INTERVAL);
+            if too_soon {
+                return Err(Error::new(
+                    ErrorKind::NotConnected,
+                    format!("Serial port {} is not connected", self.locator),
+                ));
+            }
+            self.last_open_attempt = Some(Instant::now());
+            let port = self.open().map_err(|e| {
+                Error::new(
+                    ErrorKind::NotConnected,
+                    format!("Cannot open serial port {}: {}", self.locator, e),
+                )
+            })?;
+            self.port = Some(port);
+        }
+        Ok(self.port.as_mut().expect("Opened above"))
+    }
+
+    /// Runs `op` on the port, dropping it if the error means the device is gone
+    fn with_port<R>(
+        &mut self,
+        op: impl FnOnce(&mut Box<dyn serialport::SerialPort>) -> std::io::Result<R>,
+    ) -> std::io::Result<R> {
+        let res = op(self.port()?);
+        if let Err(e) = &res {
+            if !matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) {
+                log::error!("Serial port {} failed, will reopen it: {}", self.locator, e);
+                self.port = None;
+            }
+        }
+        res
+    }
+}
+
+impl Transport for ReconnectingPort {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.with_port(|port| Transport::write_all(port, buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.with_port(Transport::flush)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.with_port(|port| Transport::read_exact(port, buf))
+    }
+
+    fn discard_input(&mut self) -> std::io::Result<usize> {
+        self.with_port(Transport::discard_input)
+    }
+}
+
+#[test]
+fn port_locators_parse_and_display() {
+    let cases = [
+        (
+            "/dev/ttyUSB0",
+            PortLocator::Path(PathBuf::from("/dev/ttyUSB0")),
+        ),
+        (
+            "by-id:usb-1a86_USB_Serial-if00-port0",
+            PortLocator::ById("usb-1a86_USB_Serial-if00-port0".to_string()),
+        ),
+        (
+            "usb:1a86:7523",
+            PortLocator::UsbId {
+                vid: 0x1a86,
+                pid: 0x7523,
+                serial_number: None,
+            },
+        ),
+        (
+            "usb:10c4:ea60:0001",
+            PortLocator::UsbId {
+                vid: 0x10c4,
+                pid: 0xea60,
+                serial_number: Some("0001".to_string()),
+            },
+        ),
+    ];
+    for (text, locator) in cases {
+        assert_eq!(text.parse::<PortLocator>().unwrap(), locator);
+        assert_eq!(locator.to_string(), text);
+    }
+    assert!("usb:1a86".parse::<PortLocator>().is_err());
+    assert!("usb:xyz:7523".parse::<PortLocator>().is_err());
+}
+
+#[test]
+fn missing_port_is_reported_as_not_connected() {
+    let mut port = ReconnectingPort::new(
+        PortLocator::Path(PathBuf::from("/dev/does-not-exist")),
+        9600,
+        Duration::from_millis(10),
+    );
+    assert!(!port.is_connected());
+    let err = Transport::write_all(&mut port, &[0]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotConnected);
+}
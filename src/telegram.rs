@@ -1,13 +1,30 @@
-use frankenstein::{Api, ChatId, InlineKeyboardButton, InlineKeyboardMarkup, TelegramApi};
+use crate::money::Money;
+use frankenstein::{
+    Api, BotCommand, BotCommandScope, BotCommandScopeChat, ChatId, InlineKeyboardButton,
+    InlineKeyboardMarkup, SetMyCommandsParams, TelegramApi,
+};
 use std::time::Duration;
 
-const TURN_ON: &'static str = "Turn On";
-const UPDATE: &'static str = "Update";
+pub mod commands;
+
+use commands::COMMANDS;
+
+/// Callback data is the callback name, optionally followed by a separator and a parameter
+const CALLBACK_SEPARATOR: char = ':';
+/// The parameter, if any, is the number of the machine
+const TURN_ON_CALLBACK: &str = "turn_on";
+const UPDATE_CALLBACK: &str = "update";
+/// The parameter is the telegram id of who asked to register
+const APPROVE_REGISTRATION_CALLBACK: &str = "approve";
+const REJECT_REGISTRATION_CALLBACK: &str = "reject";
 
 /// WARNING, there can be only one receiver at any given time
 pub struct Receiver {
     api: Api,
     id_last_update_handled: u32,
+    /// Commands in groups may be addressed to a bot, `/command@username`, fetched on the
+    /// first update
+    bot_username: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -17,6 +34,8 @@ pub struct UserMessage {
     pub message_id: Option<i32>,
     pub message_text: Option<String>,
     pub user_id: u64,
+    /// Name on the Telegram profile of who sent it
+    pub user_name: String,
     pub chat_id: i64,
     pub update: MsgType,
 }
@@ -24,12 +43,132 @@ pub struct UserMessage {
 #[derive(Debug, Clone)]
 pub enum MsgType {
     GenericMsg,
-    TurnOn,
+    /// On the given machine, or on any free one
+    TurnOn {
+        machine: Option<u8>,
+    },
     Update,
+    /// Last cycles and monthly totals
+    History {
+        cycles: u8,
+    },
+    /// Last balance transactions
+    Statement {
+        entries: u8,
+    },
+    /// Asks the admins for access, with the name to register or the profile one if none
+    Register {
+        name: Option<String>,
+    },
+    /// Admin answers to a registration request
+    ApproveRegistration {
+        telegram_id: u64,
+    },
+    RejectRegistration {
+        telegram_id: u64,
+    },
+    Admin(AdminCommand),
+    /// A known command with bad arguments, answered with how to use it
+    InvalidCommand {
+        usage: &'static str,
+    },
+    /// `/start` and `/ajuda`
+    Help,
+    /// A command not in [`COMMANDS`], without the slash
+    UnknownCommand {
+        command: String,
+    },
+}
+
+/// Only admins may run these
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Tops up the balance of a user
+    Credit {
+        telegram_id: u64,
+        amount: Money,
+    },
+    /// Gives back to a user what was charged by mistake or for a ruined cycle
+    Refund {
+        telegram_id: u64,
+        amount: Money,
+    },
+    /// Fixes the balance of a user, `amount` may be negative
+    Adjust {
+        telegram_id: u64,
+        amount: Money,
+    },
+    ListUsers,
+    /// Ends the cycle running on the given machine, or on every one
+    TurnOff {
+        machine: Option<u8>,
+    },
+    /// Who is drying and how it is going
+    Session,
+    SetPrice {
+        per_kwh: Money,
+    },
+    Block {
+        telegram_id: u64,
+    },
+    Unblock {
+        telegram_id: u64,
+    },
+}
+
+/// Buttons under the message text, pressing them comes back as a callback. Buttons sent before
+/// callbacks had parameters are still around in old messages, so the dryer ones are understood too.
+fn callback_data(data: &str) -> Option<MsgType> {
+    let (name, param) = match data.split_once(CALLBACK_SEPARATOR) {
+        Some((name, param)) => (name, Some(param)),
+        None => (data, None),
+    };
+    let telegram_id = || param?.parse().ok();
+    match name {
+        TURN_ON_CALLBACK | "Turn On" => Some(MsgType::TurnOn {
+            machine: match param {
+                Some(machine) => Some(machine.parse().ok()?),
+                None => None,
+            },
+        }),
+        UPDATE_CALLBACK | "Update" => Some(MsgType::Update),
+        APPROVE_REGISTRATION_CALLBACK => Some(MsgType::ApproveRegistration {
+            telegram_id: telegram_id()?,
+        }),
+        REJECT_REGISTRATION_CALLBACK => Some(MsgType::RejectRegistration {
+            telegram_id: telegram_id()?,
+        }),
+        _ => None,
+    }
+}
+
+/// Data of a button that comes back as `name` with `param`, Telegram allows up to 64 bytes
+fn callback_with_param(name: &str, param: impl std::fmt::Display) -> String {
+    format!("{}{}{}", name, CALLBACK_SEPARATOR, param)
+}
+
+fn profile_name(user: &frankenstein::User) -> String {
+    match &user.last_name {
+        Some(last_name) => format!("{} {}", user.first_name, last_name),
+        None => user.first_name.clone(),
+    }
 }
 
 pub struct Sender {
     api: Api,
+    /// With more than one machine each gets its own turn on button
+    machines: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buttons {
+    None,
+    /// Turn on and refresh the status
+    Dryer,
+    /// Approve or reject the registration request of a telegram id, for admins
+    Registration {
+        telegram_id: u64,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -37,14 +176,56 @@ pub struct OutgoingMessage {
     pub update_message_with_id: Option<i32>,
     pub chat_id: i64,
     pub text: String,
-    pub send_buttons: bool,
+    pub buttons: Buttons,
+}
+
+fn callback_button(text: &str, callback_data: String) -> InlineKeyboardButton {
+    InlineKeyboardButton {
+        text: text.to_string(),
+        url: None,
+        login_url: None,
+        callback_data: Some(callback_data),
+        switch_inline_query: None,
+        switch_inline_query_current_chat: None,
+        callback_game: None,
+        pay: None,
+    }
 }
 
 impl Sender {
-    pub fn new(token: String) -> Self {
+    pub fn new(token: String, machines: u8) -> Self {
         let mut api = frankenstein::Api::new(&token);
         api.with_timeout(Duration::from_secs(10));
-        Self { api }
+        Self { api, machines }
+    }
+    /// Sets the command menu shown by Telegram, users see their commands and the given chats,
+    /// the private chats of admins, see the admin ones too
+    pub fn register_commands(&self, admin_chat_ids: &[i64]) -> Result<(), frankenstein::Error> {
+        let menu = |include_admin: bool| -> Vec<BotCommand> {
+            COMMANDS
+                .iter()
+                .filter(|command| include_admin || !command.admin_only)
+                .map(|command| BotCommand {
+                    command: command.name.to_string(),
+                    description: command.description.to_string(),
+                })
+                .collect()
+        };
+        self.api.set_my_commands(&SetMyCommandsParams {
+            commands: menu(false),
+            scope: Some(BotCommandScope::Default),
+            language_code: None,
+        })?;
+        for admin_chat_id in admin_chat_ids {
+            self.api.set_my_commands(&SetMyCommandsParams {
+                commands: menu(true),
+                scope: Some(BotCommandScope::Chat(BotCommandScopeChat {
+                    chat_id: ChatId::Integer(*admin_chat_id),
+                })),
+                language_code: None,
+            })?;
+        }
+        Ok(())
     }
     pub fn start_sender_background_thread(self) -> std::sync::mpsc::SyncSender<OutgoingMessage> {
         let (sender, receiver) = std::sync::mpsc::sync_channel::<OutgoingMessage>(50);
@@ -67,36 +248,38 @@ impl Sender {
             update_message_with_id: message_id,
             chat_id,
             text,
-            send_buttons,
+            buttons,
         } = outgoing_msg;
-        let reply_markup = if send_buttons {
-            Some(InlineKeyboardMarkup {
-                inline_keyboard: vec![vec![
-                    InlineKeyboardButton {
-                        text: "Ligar".to_string(),
-                        url: None,
-                        login_url: None,
-                        callback_data: Some(TURN_ON.to_string()),
-                        switch_inline_query: None,
-                        switch_inline_query_current_chat: None,
-                        callback_game: None,
-                        pay: None,
-                    },
-                    InlineKeyboardButton {
-                        text: "Atualizar".to_string(),
-                        url: None,
-                        login_url: None,
-                        callback_data: Some(UPDATE.to_string()),
-                        switch_inline_query: None,
-                        switch_inline_query_current_chat: None,
-                        callback_game: None,
-                        pay: None,
-                    },
-                ]],
-            })
-        } else {
-            None
+        let inline_keyboard = match buttons {
+            Buttons::None => None,
+            Buttons::Dryer if self.machines <= 1 => Some(vec![vec![
+                callback_button("Ligar", TURN_ON_CALLBACK.to_string()),
+                callback_button("Atualizar", UPDATE_CALLBACK.to_string()),
+            ]]),
+            Buttons::Dryer => Some(vec![
+                (1..=self.machines)
+                    .map(|machine| {
+                        callback_button(
+                            &format!("Ligar {}", machine),
+                            callback_with_param(TURN_ON_CALLBACK, machine),
+                        )
+                    })
+                    .collect(),
+                vec![callback_button("Atualizar", UPDATE_CALLBACK.to_string())],
+            ]),
+            Buttons::Registration { telegram_id } => Some(vec![vec![
+                callback_button(
+                    "Aprovar",
+                    callback_with_param(APPROVE_REGISTRATION_CALLBACK, telegram_id),
+                ),
+                callback_button(
+                    "Rejeitar",
+                    callback_with_param(REJECT_REGISTRATION_CALLBACK, telegram_id),
+                ),
+            ]]),
         };
+        let reply_markup =
+            inline_keyboard.map(|inline_keyboard| InlineKeyboardMarkup { inline_keyboard });
         match message_id {
             None => {
                 let msg = frankenstein::SendMessageParams {
@@ -109,8 +292,7 @@ impl Sender {
                     protect_content: None,
                     reply_to_message_id: None,
                     allow_sending_without_reply: None,
-                    reply_markup: reply_markup
-                        .map(|r| frankenstein::ReplyMarkup::InlineKeyboardMarkup(r)),
+                    reply_markup: reply_markup.map(frankenstein::ReplyMarkup::InlineKeyboardMarkup),
                 };
                 self.api.send_message(&msg)?;
             }
@@ -139,6 +321,7 @@ impl Receiver {
         Self {
             api,
             id_last_update_handled: 0,
+            bot_username: None,
         }
     }
 
@@ -168,7 +351,17 @@ impl Receiver {
         receiver
     }
 
+    fn bot_username(&mut self) -> Result<String, frankenstein::Error> {
+        if let Some(username) = &self.bot_username {
+            return Ok(username.clone());
+        }
+        let username = self.api.get_me()?.result.username.unwrap_or_default();
+        self.bot_username = Some(username.clone());
+        Ok(username)
+    }
+
     fn get_updates(&mut self) -> Result<Vec<UserMessage>, frankenstein::Error> {
+        let bot_username = self.bot_username()?;
         let update_params = frankenstein::GetUpdatesParams {
             offset: Some(self.id_last_update_handled),
             limit: None,
@@ -200,25 +393,29 @@ impl Receiver {
                             message_id: None,
                             message_text: msg.text.clone(),
                             user_id: from.id,
+                            user_name: profile_name(from),
                             chat_id: msg.chat.id,
-                            update: MsgType::GenericMsg,
+                            update: commands::parse(msg.text.as_deref(), &bot_username)?,
                         }),
                     };
                 };
                 match u.callback_query {
-                    None => return None,
+                    None => None,
                     Some(callback) => {
                         let msg = callback.message?;
                         let data = callback.data?;
-                        let update = match data.as_str() {
-                            TURN_ON => MsgType::TurnOn,
-                            UPDATE => MsgType::Update,
-                            _ => return None,
+                        let update = match callback_data(&data) {
+                            Some(update) => update,
+                            None => {
+                                log::warn!("Unknown callback data `{}`", data);
+                                return None;
+                            }
                         };
                         Some(UserMessage {
                             message_id: Some(msg.message_id),
                             message_text: msg.text,
                             user_id: callback.from.id,
+                            user_name: profile_name(&callback.from),
                             chat_id: msg.chat.id,
                             update,
                         })
@@ -229,3 +426,34 @@ impl Receiver {
         Ok(parsed_updates)
     }
 }
+
+#[test]
+fn callbacks_are_parsed() {
+    assert!(matches!(
+        callback_data("turn_on"),
+        Some(MsgType::TurnOn { machine: None })
+    ));
+    assert!(matches!(
+        callback_data(&callback_with_param(TURN_ON_CALLBACK, 2)),
+        Some(MsgType::TurnOn { machine: Some(2) })
+    ));
+    assert!(callback_data("turn_on:x").is_none());
+    assert!(matches!(
+        callback_data(&callback_with_param(APPROVE_REGISTRATION_CALLBACK, 1234)),
+        Some(MsgType::ApproveRegistration { telegram_id: 1234 })
+    ));
+    assert!(matches!(
+        callback_data("reject:1234"),
+        Some(MsgType::RejectRegistration { telegram_id: 1234 })
+    ));
+    assert!(callback_data("approve:someone").is_none());
+    assert!(callback_data("approve").is_none());
+    assert!(callback_data("unknown").is_none());
+    // from buttons sent by older versions
+    assert!(matches!(
+        callback_data("Turn On"),
+        Some(MsgType::TurnOn { machine: None })
+    ));
+    assert!(matches!(callback_data("Update"), Some(MsgType::Update)));
+    assert!(callback_data("Approve 1234").is_none());
+}
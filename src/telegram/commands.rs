@@ -0,0 +1,388 @@
+use super::{AdminCommand, MsgType};
+use crate::money::Money;
+
+/// A `/command` the bot answers to, the table of them drives parsing, `/ajuda` and the menu
+/// registered with Telegram
+pub struct Command {
+    /// Without the slash
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Answered when the arguments don't parse
+    pub usage: &'static str,
+    pub admin_only: bool,
+    /// Gets everything after the command, trimmed, `None` if it isn't valid
+    parse: fn(&str) -> Option<MsgType>,
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "start",
+        description: "Como usar a secadora",
+        usage: "Uso: /start",
+        admin_only: false,
+        parse: |_| Some(MsgType::Help),
+    },
+    Command {
+        name: "ajuda",
+        description: "Lista os comandos",
+        usage: "Uso: /ajuda",
+        admin_only: false,
+        parse: |_| Some(MsgType::Help),
+    },
+    Command {
+        name: "ligar",
+        description: "Liga a secadora",
+        usage: "Uso: /ligar [NÚMERO DA SECADORA]",
+        admin_only: false,
+        parse: |args| machine(args).map(|machine| MsgType::TurnOn { machine }),
+    },
+    Command {
+        name: "status",
+        description: "Estado da secadora e seu saldo",
+        usage: "Uso: /status",
+        admin_only: false,
+        parse: |_| Some(MsgType::Update),
+    },
+    Command {
+        name: "historico",
+        description: "Últimos ciclos e consumo por mês",
+        usage: "Uso: /historico [N]",
+        admin_only: false,
+        parse: |args| {
+            Some(MsgType::History {
+                cycles: count(args),
+            })
+        },
+    },
+    Command {
+        name: "extrato",
+        description: "Últimas movimentações do saldo",
+        usage: "Uso: /extrato [N]",
+        admin_only: false,
+        parse: |args| {
+            Some(MsgType::Statement {
+                entries: count(args),
+            })
+        },
+    },
+    Command {
+        name: "registrar",
+        description: "Pede acesso à secadora",
+        usage: "Uso: /registrar [SEU NOME]",
+        admin_only: false,
+        parse: |name| {
+            Some(MsgType::Register {
+                name: (!name.is_empty()).then(|| name.to_string()),
+            })
+        },
+    },
+    Command {
+        name: "creditar",
+        description: "Credita saldo para um usuário",
+        usage: "Uso: /creditar ID VALOR, como /creditar 123456 20,00",
+        admin_only: true,
+        parse: |args| {
+            id_and_amount(args)
+                .filter(|(_, amount)| *amount > Money::ZERO)
+                .map(|(telegram_id, amount)| {
+                    MsgType::Admin(AdminCommand::Credit {
+                        telegram_id,
+                        amount,
+                    })
+                })
+        },
+    },
+    Command {
+        name: "reembolsar",
+        description: "Devolve a um usuário o que foi cobrado",
+        usage: "Uso: /reembolsar ID VALOR, como /reembolsar 123456 2,50",
+        admin_only: true,
+        parse: |args| {
+            id_and_amount(args)
+                .filter(|(_, amount)| *amount > Money::ZERO)
+                .map(|(telegram_id, amount)| {
+                    MsgType::Admin(AdminCommand::Refund {
+                        telegram_id,
+                        amount,
+                    })
+                })
+        },
+    },
+    Command {
+        name: "ajustar",
+        description: "Corrige o saldo de um usuário, para mais ou para menos",
+        usage: "Uso: /ajustar ID VALOR, como /ajustar 123456 -1,50",
+        admin_only: true,
+        parse: |args| {
+            id_and_amount(args)
+                .filter(|(_, amount)| *amount != Money::ZERO)
+                .map(|(telegram_id, amount)| {
+                    MsgType::Admin(AdminCommand::Adjust {
+                        telegram_id,
+                        amount,
+                    })
+                })
+        },
+    },
+    Command {
+        name: "usuarios",
+        description: "Lista os usuários e seus saldos",
+        usage: "Uso: /usuarios",
+        admin_only: true,
+        parse: |_| Some(MsgType::Admin(AdminCommand::ListUsers)),
+    },
+    Command {
+        name: "desligar",
+        description: "Desliga a secadora, encerrando o ciclo",
+        usage: "Uso: /desligar [NÚMERO DA SECADORA]",
+        admin_only: true,
+        parse: |args| {
+            machine(args).map(|machine| MsgType::Admin(AdminCommand::TurnOff { machine }))
+        },
+    },
+    Command {
+        name: "sessao",
+        description: "Mostra o ciclo em andamento",
+        usage: "Uso: /sessao",
+        admin_only: true,
+        parse: |_| Some(MsgType::Admin(AdminCommand::Session)),
+    },
+    Command {
+        name: "preco",
+        description: "Muda o preço do kWh",
+        usage: "Uso: /preco VALOR_DO_KWH, como /preco 1,10",
+        admin_only: true,
+        parse: |args| match words(args)[..] {
+            [price] => price
+                .parse()
+                .ok()
+                .filter(|per_kwh| *per_kwh > Money::ZERO)
+                .map(|per_kwh| MsgType::Admin(AdminCommand::SetPrice { per_kwh })),
+            _ => None,
+        },
+    },
+    Command {
+        name: "bloquear",
+        description: "Bloqueia um usuário",
+        usage: "Uso: /bloquear ID",
+        admin_only: true,
+        parse: |args| match words(args)[..] {
+            [id] => id
+                .parse()
+                .ok()
+                .map(|telegram_id| MsgType::Admin(AdminCommand::Block { telegram_id })),
+            _ => None,
+        },
+    },
+    Command {
+        name: "desbloquear",
+        description: "Desbloqueia um usuário",
+        usage: "Uso: /desbloquear ID",
+        admin_only: true,
+        parse: |args| match words(args)[..] {
+            [id] => id
+                .parse()
+                .ok()
+                .map(|telegram_id| MsgType::Admin(AdminCommand::Unblock { telegram_id })),
+            _ => None,
+        },
+    },
+];
+
+fn words(args: &str) -> Vec<&str> {
+    args.split_whitespace().collect()
+}
+
+/// `ID VALOR`, of the commands moving a balance
+fn id_and_amount(args: &str) -> Option<(u64, Money)> {
+    match words(args)[..] {
+        [id, amount] => id.parse().ok().zip(amount.parse().ok()),
+        _ => None,
+    }
+}
+
+/// Machine number the command is for, `Some(None)` if none was given
+fn machine(args: &str) -> Option<Option<u8>> {
+    match words(args)[..] {
+        [] => Some(None),
+        [machine] => machine
+            .parse()
+            .ok()
+            .filter(|machine| *machine > 0)
+            .map(Some),
+        _ => None,
+    }
+}
+
+/// How many entries to list, invalid or missing counts fall back to the default
+fn count(args: &str) -> u8 {
+    words(args)
+        .first()
+        .and_then(|n| n.parse::<u32>().ok())
+        .map(|n| n.clamp(1, crate::history::MAX_ENTRIES.into()) as u8)
+        .unwrap_or(crate::history::DEFAULT_ENTRIES)
+}
+
+/// `/command args` or, in groups, `/command@bot args`, looked up in [`COMMANDS`]. Anything not
+/// starting with a slash is a generic message.
+///
+/// `None` for commands addressed to another bot, those are not for us to answer.
+pub fn parse(text: Option<&str>, bot_username: &str) -> Option<MsgType> {
+    let text = text.unwrap_or_default().trim();
+    let command = match text.strip_prefix('/') {
+        Some(command) => command,
+        None => return Some(MsgType::GenericMsg),
+    };
+    let (command, args) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let name = match command.split_once('@') {
+        Some((name, username)) if username.eq_ignore_ascii_case(bot_username) => name,
+        Some(_) => return None,
+        None => command,
+    };
+    let parsed = match COMMANDS.iter().find(|c| c.name == name) {
+        Some(command) => (command.parse)(args.trim()).unwrap_or(MsgType::InvalidCommand {
+            usage: command.usage,
+        }),
+        None => MsgType::UnknownCommand {
+            command: name.to_string(),
+        },
+    };
+    Some(parsed)
+}
+
+/// Answer to `/ajuda`, admins also see their commands
+pub fn help(is_admin: bool) -> String {
+    let lines: Vec<String> = COMMANDS
+        .iter()
+        .filter(|command| is_admin || !command.admin_only)
+        .map(|command| format!("/{} - {}", command.name, command.description))
+        .collect();
+    format!(
+        "Use os botões para ligar a secadora e atualizar o estado dela, ou os comandos:\n{}",
+        lines.join("\n")
+    )
+}
+
+#[cfg(test)]
+const BOT_USERNAME: &str = "DringosBot";
+
+#[test]
+fn history_commands_are_parsed() {
+    assert!(matches!(
+        parse(Some("/historico"), BOT_USERNAME),
+        Some(MsgType::History { cycles: 5 })
+    ));
+    assert!(matches!(
+        parse(Some("/historico 12"), BOT_USERNAME),
+        Some(MsgType::History { cycles: 12 })
+    ));
+    assert!(matches!(
+        parse(Some("/historico 500"), BOT_USERNAME),
+        Some(MsgType::History { cycles: 20 })
+    ));
+    assert!(matches!(
+        parse(Some("/extrato 3"), BOT_USERNAME),
+        Some(MsgType::Statement { entries: 3 })
+    ));
+    assert!(matches!(
+        parse(Some("oi"), BOT_USERNAME),
+        Some(MsgType::GenericMsg)
+    ));
+    assert!(matches!(
+        parse(None, BOT_USERNAME),
+        Some(MsgType::GenericMsg)
+    ));
+}
+
+#[test]
+fn registration_is_parsed() {
+    assert!(matches!(
+        parse(Some("/registrar"), BOT_USERNAME),
+        Some(MsgType::Register { name: None })
+    ));
+    assert!(
+        matches!(parse(Some("/registrar  Maria da Silva "), BOT_USERNAME), Some(MsgType::Register { name: Some(name) }) if name == "Maria da Silva")
+    );
+    assert!(
+        matches!(parse(Some("/registrarme"), BOT_USERNAME), Some(MsgType::UnknownCommand { command }) if command == "registrarme")
+    );
+}
+
+#[test]
+fn admin_commands_are_parsed() {
+    assert!(matches!(
+        parse(Some("/creditar 1234 20,50"), BOT_USERNAME),
+        Some(MsgType::Admin(AdminCommand::Credit { telegram_id: 1234, amount })) if amount == Money::from_centavos(2050)
+    ));
+    assert!(matches!(
+        parse(Some("/creditar 1234 -5"), BOT_USERNAME),
+        Some(MsgType::InvalidCommand { .. })
+    ));
+    assert!(matches!(
+        parse(Some("/reembolsar 1234 2,50"), BOT_USERNAME),
+        Some(MsgType::Admin(AdminCommand::Refund { telegram_id: 1234, amount })) if amount == Money::from_centavos(250)
+    ));
+    assert!(matches!(
+        parse(Some("/ajustar 1234 -1,50"), BOT_USERNAME),
+        Some(MsgType::Admin(AdminCommand::Adjust { telegram_id: 1234, amount })) if amount == Money::from_centavos(-150)
+    ));
+    assert!(matches!(
+        parse(Some("/ajustar 1234 0"), BOT_USERNAME),
+        Some(MsgType::InvalidCommand { .. })
+    ));
+    assert!(matches!(
+        parse(Some("/preco 1,10"), BOT_USERNAME),
+        Some(MsgType::Admin(AdminCommand::SetPrice { per_kwh })) if per_kwh == Money::from_centavos(110)
+    ));
+    assert!(matches!(
+        parse(Some("/bloquear"), BOT_USERNAME),
+        Some(MsgType::InvalidCommand {
+            usage: "Uso: /bloquear ID"
+        })
+    ));
+    assert!(matches!(
+        parse(Some("/desbloquear 99"), BOT_USERNAME),
+        Some(MsgType::Admin(AdminCommand::Unblock { telegram_id: 99 }))
+    ));
+    assert!(matches!(
+        parse(Some("/desligar"), BOT_USERNAME),
+        Some(MsgType::Admin(AdminCommand::TurnOff { machine: None }))
+    ));
+    assert!(matches!(
+        parse(Some("/desligar 2"), BOT_USERNAME),
+        Some(MsgType::Admin(AdminCommand::TurnOff { machine: Some(2) }))
+    ));
+    assert!(matches!(
+        parse(Some("/sessao"), BOT_USERNAME),
+        Some(MsgType::Admin(AdminCommand::Session))
+    ));
+}
+
+#[test]
+fn commands_addressed_to_other_bots_are_ignored() {
+    assert!(matches!(
+        parse(Some("/historico@dringosbot 3"), BOT_USERNAME),
+        Some(MsgType::History { cycles: 3 })
+    ));
+    assert!(matches!(
+        parse(Some("/ligar@DringosBot"), BOT_USERNAME),
+        Some(MsgType::TurnOn { machine: None })
+    ));
+    assert!(matches!(
+        parse(Some("/ligar 2"), BOT_USERNAME),
+        Some(MsgType::TurnOn { machine: Some(2) })
+    ));
+    assert!(matches!(
+        parse(Some("/ligar 0"), BOT_USERNAME),
+        Some(MsgType::InvalidCommand { .. })
+    ));
+    assert!(parse(Some("/ligar@OtherBot"), BOT_USERNAME).is_none());
+    assert!(matches!(
+        parse(Some("/start"), BOT_USERNAME),
+        Some(MsgType::Help)
+    ));
+    assert!(!help(false).contains("/creditar"));
+    assert!(help(true).contains("/creditar"));
+}
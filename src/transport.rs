@@ -0,0 +1,149 @@
+use serialport::ClearBuffer;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Minimal byte pipe the Modbus RTU layer talks through.
+///
+/// Implemented for serial ports, TCP-to-RTU gateways (raw RTU frames over a TCP socket) and
+/// an in-memory scripted transport used by the tests.
+pub trait Transport {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()>;
+    fn flush(&mut self) -> std::io::Result<()>;
+    /// Blocks until `buf` is filled or the transport read timeout expires, in which case an
+    /// error of kind `TimedOut` (or `WouldBlock` for sockets) is returned.
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()>;
+    /// Throws away anything pending on the line, returns how many bytes were discarded.
+    fn discard_input(&mut self) -> std::io::Result<usize>;
+}
+
+impl Transport for Box<dyn serialport::SerialPort> {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        Write::write_all(self, buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Write::flush(self)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        Read::read_exact(self, buf)
+    }
+
+    fn discard_input(&mut self) -> std::io::Result<usize> {
+        let pending = self.bytes_to_read()?;
+        self.clear(ClearBuffer::All)?;
+        Ok(pending as usize)
+    }
+}
+
+/// Raw RTU frames tunneled over TCP, as done by most cheap RS-485 to Ethernet/WiFi gateways
+/// when configured in "transparent" mode.
+#[derive(Debug)]
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect<A: ToSocketAddrs>(addr: A, timeout: Duration) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.stream.write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.stream.read_exact(buf)
+    }
+
+    fn discard_input(&mut self) -> std::io::Result<usize> {
+        self.stream.set_nonblocking(true)?;
+        let mut discarded = 0;
+        let mut scratch = [0; 64];
+        let res = loop {
+            match self.stream.read(&mut scratch) {
+                Ok(0) => {
+                    break Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "TCP gateway closed the connection",
+                    ))
+                }
+                Ok(n) => discarded += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(discarded),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        res
+    }
+}
+
+/// In-memory transport: every frame written to it "receives" the next scripted reply.
+///
+/// Reading more than what is available behaves like a serial read timeout.
+#[derive(Debug, Default)]
+pub struct MemoryTransport {
+    replies: VecDeque<Vec<u8>>,
+    rx: VecDeque<u8>,
+    pub written: Vec<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a reply that will become readable after the next write.
+    /// An empty reply simulates a device that doesn't answer.
+    pub fn push_reply(&mut self, reply: &[u8]) {
+        self.replies.push_back(reply.to_vec());
+    }
+
+    /// Makes bytes readable right away, as if noise or a late reply was on the line.
+    pub fn push_garbage(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes);
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.written.push(buf.to_vec());
+        if let Some(reply) = self.replies.pop_front() {
+            self.rx.extend(reply);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        if self.rx.len() < buf.len() {
+            self.rx.clear();
+            return Err(Error::new(ErrorKind::TimedOut, "Operation timed out"));
+        }
+        for b in buf.iter_mut() {
+            *b = self.rx.pop_front().expect("Checked length above");
+        }
+        Ok(())
+    }
+
+    fn discard_input(&mut self) -> std::io::Result<usize> {
+        let n = self.rx.len();
+        self.rx.clear();
+        Ok(n)
+    }
+}